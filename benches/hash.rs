@@ -0,0 +1,41 @@
+//! Hashing throughput of a large file.
+//!
+//! The file is written once and stays in the page cache, so the numbers are
+//! the upper bound of the hashing itself. Compare them with the read speed
+//! of the disk, eg. `dd if=<file> of=/dev/null bs=4M` after dropping the
+//! page cache, to see whether the uploads are bound by the hashing.
+use cfs::hash::DigestFunction;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+const FILE_SIZE: usize = 256 * 1024 * 1024;
+
+fn create_file() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("cfs-bench-hash-{}", std::process::id()));
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    fs::write(&path, data).unwrap();
+    path
+}
+
+fn bench_hash(c: &mut Criterion) {
+    let path = create_file();
+    let mut group = c.benchmark_group("hash");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+
+    for f in [DigestFunction::Sha256, DigestFunction::Blake3] {
+        group.bench_function(format!("{}/hash_read", f.name()), |b| {
+            b.iter(|| f.hash_read(&mut File::open(&path).unwrap()).unwrap())
+        });
+        group.bench_function(format!("{}/hash_file", f.name()), |b| {
+            b.iter(|| f.hash_file(&path).unwrap())
+        });
+    }
+    group.finish();
+
+    fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, bench_hash);
+criterion_main!(benches);
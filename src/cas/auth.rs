@@ -1,4 +1,4 @@
-use anyhow::Result;
+use super::error::Error;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -7,28 +7,35 @@ use tonic::{metadata::MetadataValue, Request, Status};
 
 #[derive(Clone)]
 pub struct AuthInterceptor {
-    token: String,
+    /// None for the plaintext endpoints, eg. a local test server, so that
+    /// the token is never sent unencrypted
+    token: Option<String>,
 }
 
 impl AuthInterceptor {
-    pub fn new() -> Result<Self> {
-        let home_dir = env::var("HOME")?;
-        let token_path = format!("{}/.rbe-auth-token", home_dir);
-        let mut token = String::new();
-        let mut file = File::open(&token_path).map_err(|e| {
-            anyhow::Error::msg(format!(
-                "failed to open auth token {}: {}",
-                token_path, e
-            ))
-        })?;
-        file.read_to_string(&mut token)?;
-        Ok(AuthInterceptor { token: token })
+    pub fn new(token: Option<String>) -> Self {
+        AuthInterceptor { token }
     }
 }
 
+/// read the auth token from `~/.rbe-auth-token`
+pub(crate) fn read_token() -> Result<String, Error> {
+    let home_dir = env::var("HOME").map_err(|_| Error::config("HOME is not set"))?;
+    let token_path = format!("{}/.rbe-auth-token", home_dir);
+    let mut token = String::new();
+    let mut file = File::open(&token_path)
+        .map_err(|e| Error::config(format!("failed to open auth token {}: {}", token_path, e)))?;
+    file.read_to_string(&mut token)?;
+    Ok(token)
+}
+
 impl Interceptor for AuthInterceptor {
     fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
-        let bearer_token = format!("Bearer {}", self.token);
+        let token = match &self.token {
+            Some(token) => token,
+            None => return Ok(request),
+        };
+        let bearer_token = format!("Bearer {}", token);
         let header_value = MetadataValue::from_str(&bearer_token)
             .map_err(|_e| Status::invalid_argument("auth token is invalid"))?;
         request
@@ -0,0 +1,162 @@
+//! Grouping of the blobs into batch requests.
+//!
+//! BatchUpdateBlobs and BatchReadBlobs carry many blobs in one request, up
+//! to the max batch size of the server. The blobs are grouped in the order
+//! they come, and blobs too large for any batch are left to ByteStream.
+use std::mem;
+
+/// rough size of the per blob framing in the batch requests and responses
+pub(crate) const BATCH_ENTRY_OVERHEAD: i64 = 256;
+
+/// Limit the batch size to 2000 to avoid BatchUpdateBlob bug
+pub(crate) const MAX_BATCH_COUNT: usize = 2000;
+
+/// BatchPlanner groups the blobs into batches within the byte and count
+/// limits of a batch request.
+///
+/// A batch is handed out once the next blob does not fit into it, so that
+/// every batch is as full as the order of the blobs allows and the tail is
+/// left in a single batch.
+pub(crate) struct BatchPlanner<T> {
+    max_bytes: i64,
+    max_count: usize,
+    pending: Vec<T>,
+    pending_bytes: i64,
+}
+
+impl<T> BatchPlanner<T> {
+    pub fn new(max_bytes: i64, max_count: usize) -> BatchPlanner<T> {
+        BatchPlanner {
+            max_bytes,
+            max_count: max_count.max(1),
+            pending: vec![],
+            pending_bytes: 0,
+        }
+    }
+
+    /// whether a blob of the size fits into a batch at all, larger blobs
+    /// have to be streamed
+    pub fn fits(&self, size: i64) -> bool {
+        size + BATCH_ENTRY_OVERHEAD <= self.max_bytes
+    }
+
+    /// add the blob to the pending batch. Returns the pending batch when the
+    /// blob does not fit into it anymore, the blob then starts the next one.
+    ///
+    /// The blob must fit into a batch, see `fits`.
+    pub fn push(&mut self, item: T, size: i64) -> Option<Vec<T>> {
+        debug_assert!(self.fits(size));
+        let size = size + BATCH_ENTRY_OVERHEAD;
+        let full =
+            self.pending_bytes + size > self.max_bytes || self.pending.len() >= self.max_count;
+        let batch = if full { self.flush() } else { None };
+        self.pending.push(item);
+        self.pending_bytes += size;
+        batch
+    }
+
+    /// take the pending batch, eg. the tail once there are no more blobs
+    pub fn flush(&mut self) -> Option<Vec<T>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.pending_bytes = 0;
+        Some(mem::take(&mut self.pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// plan the batches of the sizes, returns the indexes of the blobs in
+    /// each batch
+    fn plan(sizes: &[i64], max_bytes: i64, max_count: usize) -> Vec<Vec<usize>> {
+        let mut planner = BatchPlanner::new(max_bytes, max_count);
+        let mut batches = vec![];
+        for (i, size) in sizes.iter().enumerate() {
+            assert!(planner.fits(*size));
+            batches.extend(planner.push(i, *size));
+        }
+        batches.extend(planner.flush());
+        batches
+    }
+
+    fn batch_bytes(sizes: &[i64], batch: &[usize]) -> i64 {
+        batch.iter().map(|i| sizes[*i] + BATCH_ENTRY_OVERHEAD).sum()
+    }
+
+    #[test]
+    fn test_exact_limits() {
+        let max = 2 * (100 + BATCH_ENTRY_OVERHEAD);
+        // two blobs fill a batch exactly
+        assert_eq!(plan(&[100, 100, 100], max, 10), vec![vec![0, 1], vec![2]]);
+        // one more byte does not fit
+        assert_eq!(plan(&[100, 101, 100], max, 10), vec![vec![0], vec![1, 2]]);
+        // exactly max_count blobs in a batch
+        assert_eq!(
+            plan(&[1, 1, 1, 1, 1], max, 2),
+            vec![vec![0, 1], vec![2, 3], vec![4]]
+        );
+    }
+
+    #[test]
+    fn test_tail_in_a_single_batch() {
+        let mut planner = BatchPlanner::new(1 << 20, 100);
+        for i in 0..10 {
+            assert!(planner.push(i, 10).is_none());
+        }
+        assert_eq!(planner.flush(), Some((0..10).collect()));
+        assert_eq!(planner.flush(), None);
+    }
+
+    #[test]
+    fn test_fits() {
+        let planner = BatchPlanner::<()>::new(1000, 10);
+        assert!(planner.fits(1000 - BATCH_ENTRY_OVERHEAD));
+        assert!(!planner.fits(1000 - BATCH_ENTRY_OVERHEAD + 1));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_batches_within_limits(
+            sizes in prop::collection::vec(0i64..4096, 0..200),
+            max_count in 1usize..20,
+        ) {
+            let max_bytes = 4096 + BATCH_ENTRY_OVERHEAD;
+            for batch in plan(&sizes, max_bytes, max_count) {
+                prop_assert!(!batch.is_empty());
+                prop_assert!(batch.len() <= max_count);
+                prop_assert!(batch_bytes(&sizes, &batch) <= max_bytes);
+            }
+        }
+
+        #[test]
+        fn prop_blobs_kept_in_order(
+            sizes in prop::collection::vec(0i64..4096, 0..200),
+            max_count in 1usize..20,
+        ) {
+            let batches = plan(&sizes, 4096 + BATCH_ENTRY_OVERHEAD, max_count);
+            let indexes: Vec<_> = batches.into_iter().flatten().collect();
+            prop_assert_eq!(indexes, (0..sizes.len()).collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn prop_batches_are_full(
+            sizes in prop::collection::vec(0i64..4096, 0..200),
+            max_count in 1usize..20,
+        ) {
+            // a batch is only cut when the first blob of the next one does not fit
+            let max_bytes = 4096 + BATCH_ENTRY_OVERHEAD;
+            let batches = plan(&sizes, max_bytes, max_count);
+            for pair in batches.windows(2) {
+                let next = pair[1][0];
+                prop_assert!(
+                    pair[0].len() == max_count
+                        || batch_bytes(&sizes, &pair[0]) + sizes[next] + BATCH_ENTRY_OVERHEAD > max_bytes
+                );
+            }
+        }
+    }
+}
@@ -1,63 +1,79 @@
-use super::auth::AuthInterceptor;
+use super::batch::{BatchPlanner, MAX_BATCH_COUNT};
+use super::capabilities::Capabilities;
+use super::client::{Client as AsyncClient, ClientBuilder};
+use super::error::{Error, Result};
 use crate::git::get_git_root;
 use crate::git::get_lfs_object_path;
 use crate::git::git_lfs_fetch;
+use crate::hash::DigestFunction;
 use crate::lfs::LfsFile;
-use anyhow::Result;
-use bazel_remote_apis_rs::build::bazel::remote::execution::v2::content_addressable_storage_client::*;
+use crate::metrics;
 use bazel_remote_apis_rs::build::bazel::remote::execution::v2::*;
-use bazel_remote_apis_rs::google::bytestream::byte_stream_client::ByteStreamClient;
-use bazel_remote_apis_rs::google::bytestream::{ReadRequest, WriteRequest};
-use futures::Stream;
 use prost::Message;
-use std::collections::hash_map::Entry;
+use serde::{Deserialize, Serialize};
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::env;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
-use std::pin::Pin;
 use std::str;
-use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
+use std::time::Duration;
 use tokio::fs::File;
-use tokio::io::ReadBuf;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc;
-use tonic::service::interceptor::InterceptedService;
-use tonic::transport::{Certificate, Channel, ClientTlsConfig};
-use uuid::Uuid;
-
-type CasClient = ContentAddressableStorageClient<InterceptedService<Channel, AuthInterceptor>>;
-
-type BsClient = ByteStreamClient<InterceptedService<Channel, AuthInterceptor>>;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+use tracing::{debug, error, info, warn};
+
+pub use super::client::{ClientOptions, UploadOptions};
+
+/// fetch the capabilities of the server without creating a client
+pub fn get_server_capabilities() -> Result<Capabilities> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let client = rt.block_on(ClientBuilder::new().connect())?;
+    Ok(client.capabilities().clone())
+}
 
+/// Client is the blocking version of [`AsyncClient`] on its own runtime
 pub struct Client {
-    inner: CasClient,
-    bs_client: BsClient,
+    inner: AsyncClient,
     rt: Runtime,
 }
 
 impl Client {
     pub fn new() -> Result<Client> {
+        Client::with_options(ClientOptions::default())
+    }
+
+    pub fn with_options(options: ClientOptions) -> Result<Client> {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()?;
-        let inner = rt.block_on(create_cas_client())?;
-        let bs_client = rt.block_on(create_bs_client())?;
-        Ok(Self {
-            inner,
-            rt,
-            bs_client,
-        })
+        let inner = rt.block_on(ClientBuilder::new().options(options).connect())?;
+        Ok(Self { inner, rt })
+    }
+
+    /// the capabilities of the server fetched on connect
+    pub fn capabilities(&self) -> &Capabilities {
+        self.inner.capabilities()
+    }
+
+    /// the hash function of the digests agreed with the server
+    pub fn digest_function(&self) -> DigestFunction {
+        self.inner.digest_function()
     }
 
     /// get all the directories descended from the requested root digest
     pub fn get_tree(&mut self, hash: &str, size: i64) -> Result<Vec<Directory>> {
         self.rt
-            .block_on(read_directories(&mut self.inner, hash, size))
+            .block_on(self.inner.get_tree(&digest(hash, size), 0))
     }
 
     /// get the content of a single blob
@@ -77,16 +93,12 @@ impl Client {
 
     pub fn write_blob(&mut self, digest: &Digest, buff: &[u8]) -> Result<()> {
         self.rt
-            .block_on(bs_write_blob(&mut self.bs_client, digest, buff.to_vec()))
+            .block_on(self.inner.write_blob(digest, buff.to_vec()))
     }
 
     /// writes a large file into CAS
     pub fn write_file(&mut self, digest: &Digest, path: &Path) -> Result<()> {
-        self.rt.block_on(bs_write_file(
-            &mut self.bs_client,
-            &digest,
-            path.to_path_buf(),
-        ))
+        self.rt.block_on(self.inner.write_file(digest, path))
     }
 }
 
@@ -114,25 +126,126 @@ pub struct NonBlockingClient {
     sender: mpsc::Sender<WriteTask>,
 }
 
-pub fn spawn_receiver() -> (mpsc::Sender<WriteTask>, JoinHandle<()>) {
+/// UploadStats counts the blobs going through the upload pipeline of
+/// [`spawn_receiver`], so that the progress could be shown while it runs
+#[derive(Debug, Default)]
+pub struct UploadStats {
+    checked_blobs: AtomicU64,
+    checked_bytes: AtomicU64,
+    missing_blobs: AtomicU64,
+    missing_bytes: AtomicU64,
+    uploaded_blobs: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    failed_blobs: AtomicU64,
+    find_missing_time: Mutex<Duration>,
+    /// start of the first upload and end of the last one
+    upload_window: Mutex<Option<(Instant, Instant)>>,
+}
+
+/// UploadCounts is a snapshot of the [`UploadStats`], the sizes are the
+/// sizes of the digests, before compression
+#[derive(Debug, Clone, Default)]
+pub struct UploadCounts {
+    /// blobs checked against the server with FindMissingBlobs
+    pub checked_blobs: u64,
+    pub checked_bytes: u64,
+    /// blobs found missing from the server
+    pub missing_blobs: u64,
+    pub missing_bytes: u64,
+    pub uploaded_blobs: u64,
+    pub uploaded_bytes: u64,
+    /// blobs failed to read or to upload
+    pub failed_blobs: u64,
+    /// time spent in FindMissingBlobs
+    pub find_missing_time: Duration,
+    /// time from the start of the first upload to the end of the last one
+    pub upload_time: Duration,
+}
+
+impl UploadStats {
+    pub fn snapshot(&self) -> UploadCounts {
+        UploadCounts {
+            checked_blobs: self.checked_blobs.load(Ordering::Relaxed),
+            checked_bytes: self.checked_bytes.load(Ordering::Relaxed),
+            missing_blobs: self.missing_blobs.load(Ordering::Relaxed),
+            missing_bytes: self.missing_bytes.load(Ordering::Relaxed),
+            uploaded_blobs: self.uploaded_blobs.load(Ordering::Relaxed),
+            uploaded_bytes: self.uploaded_bytes.load(Ordering::Relaxed),
+            failed_blobs: self.failed_blobs.load(Ordering::Relaxed),
+            find_missing_time: *self.find_missing_time.lock().unwrap(),
+            upload_time: self
+                .upload_window
+                .lock()
+                .unwrap()
+                .map_or(Duration::ZERO, |(start, end)| end - start),
+        }
+    }
+
+    fn checked(&self, pending: &[WriteTask], missing: &[WriteTask], elapsed: Duration) {
+        self.checked_blobs
+            .fetch_add(pending.len() as u64, Ordering::Relaxed);
+        self.checked_bytes
+            .fetch_add(task_bytes(pending), Ordering::Relaxed);
+        self.missing_blobs
+            .fetch_add(missing.len() as u64, Ordering::Relaxed);
+        self.missing_bytes
+            .fetch_add(task_bytes(missing), Ordering::Relaxed);
+        *self.find_missing_time.lock().unwrap() += elapsed;
+    }
+
+    fn uploaded(&self, blobs: u64, bytes: i64, start: Instant) {
+        self.uploaded_blobs.fetch_add(blobs, Ordering::Relaxed);
+        self.uploaded_bytes
+            .fetch_add(bytes.max(0) as u64, Ordering::Relaxed);
+        let mut window = self.upload_window.lock().unwrap();
+        let (first, _) = window.unwrap_or((start, start));
+        *window = Some((first.min(start), Instant::now()));
+    }
+
+    fn failed(&self, blobs: u64) {
+        self.failed_blobs.fetch_add(blobs, Ordering::Relaxed);
+    }
+}
+
+fn task_bytes(tasks: &[WriteTask]) -> u64 {
+    tasks
+        .iter()
+        .map(|t| match t {
+            WriteTask::WriteBlob(WriteBlob { digest, .. }) => digest,
+            WriteTask::WriteFile(WriteFile { digest, .. }) => digest,
+        })
+        .map(|d| d.size_bytes.max(0) as u64)
+        .sum()
+}
+
+/// spawn the thread uploading the blobs sent to the returned sender, the
+/// blobs already in CAS are skipped. The thread exits once all of the
+/// senders are dropped and the uploads are done. The client connects
+/// before the thread is spawned, so connection errors are returned here
+pub fn spawn_receiver(
+    options: ClientOptions,
+    stats: Arc<UploadStats>,
+) -> Result<(mpsc::Sender<WriteTask>, JoinHandle<()>)> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let client = rt.block_on(ClientBuilder::new().options(options).connect())?;
+
     let (send, recv) = mpsc::channel(1024);
     let (ft_send, ft_recv) = mpsc::channel(1024);
     let handle = std::thread::spawn(move || {
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-
+        let filter_client = client.clone();
+        let filter_stats = stats.clone();
         rt.spawn(async move {
-            filter_loop(recv, ft_send).await;
+            filter_loop(recv, ft_send, filter_client, filter_stats).await;
         });
 
         rt.block_on(async move {
-            receiver_loop(ft_recv).await;
+            receiver_loop(ft_recv, client, stats).await;
         });
     });
 
-    (send, handle)
+    Ok((send, handle))
 }
 
 async fn identical_filter_loop(mut recv: mpsc::Receiver<WriteTask>, send: mpsc::Sender<WriteTask>) {
@@ -143,75 +256,94 @@ async fn identical_filter_loop(mut recv: mpsc::Receiver<WriteTask>, send: mpsc::
     //println!("identical filter loop done");
 }
 
-async fn filter_loop(mut recv: mpsc::Receiver<WriteTask>, send: mpsc::Sender<WriteTask>) {
-    let mut pending = vec![];
-    let mut bt_client = create_cas_client().await.unwrap();
-    while let Some(task) = recv.recv().await {
-        pending.push(task);
+/// FindMissingBlobs is sent once this many digests are pending, or once
+/// the first pending digest waited for `FIND_MISSING_BLOBS_INTERVAL`, so
+/// that the uploads start while the rest of the tree is still being hashed
+const FIND_MISSING_BLOBS_COUNT: usize = 1000;
 
-        // max gRPC message size is 4MiB and each request is (digest 64B + instance name 6B)
-        if pending.len() >= 50000 {
-            let mut filtered = vec![];
-            filtered.append(&mut pending);
-            let filtered = find_missing_blobs(&mut bt_client, filtered).await;
+const FIND_MISSING_BLOBS_INTERVAL: Duration = Duration::from_millis(100);
+
+async fn filter_loop(
+    mut recv: mpsc::Receiver<WriteTask>,
+    send: mpsc::Sender<WriteTask>,
+    client: AsyncClient,
+    stats: Arc<UploadStats>,
+) {
+    let mut pending = vec![];
+    let mut deadline = None;
+    loop {
+        let task = match deadline {
+            Some(deadline) => time::timeout_at(deadline, recv.recv()).await,
+            None => Ok(recv.recv().await),
+        };
+        let closed = match task {
+            Ok(Some(task)) => {
+                if pending.is_empty() {
+                    deadline = Some(Instant::now() + FIND_MISSING_BLOBS_INTERVAL);
+                }
+                pending.push(task);
+                if pending.len() < FIND_MISSING_BLOBS_COUNT {
+                    continue;
+                }
+                false
+            }
+            // the first pending digest has waited long enough
+            Err(_) => false,
+            // all of the senders are gone
+            Ok(None) => true,
+        };
 
+        deadline = None;
+        if !pending.is_empty() {
+            let filtered = find_missing_blobs(&client, mem::take(&mut pending), &stats).await;
             for t in filtered {
                 let res = send.send(t).await;
                 if res.is_err() {
-                    println!("failed to send the message {:?}", res.unwrap_err());
-                    break;
+                    warn!("failed to send the message {:?}", res.unwrap_err());
+                    return;
                 }
             }
         }
-    }
-
-    let mut filtered = vec![];
-    filtered.append(&mut pending);
-    let filtered = find_missing_blobs(&mut bt_client, filtered).await;
-
-    for t in filtered {
-        let res = send.send(t).await;
-        if res.is_err() {
-            println!("failed to send the message {:?}", res.unwrap_err());
+        if closed {
+            break;
         }
     }
     //println!("Missing blobs filter loop done");
 }
 
-async fn find_missing_blobs(bt_client: &mut CasClient, pending: Vec<WriteTask>) -> Vec<WriteTask> {
+async fn find_missing_blobs(
+    client: &AsyncClient,
+    pending: Vec<WriteTask>,
+    stats: &UploadStats,
+) -> Vec<WriteTask> {
+    let start = Instant::now();
     let mut digests = vec![];
-    for p in pending.clone() {
+    for p in &pending {
         match p {
-            WriteTask::WriteBlob(WriteBlob { digest, .. }) => digests.push(digest),
-            WriteTask::WriteFile(WriteFile { digest, .. }) => digests.push(digest),
+            WriteTask::WriteBlob(WriteBlob { digest, .. }) => digests.push(digest.clone()),
+            WriteTask::WriteFile(WriteFile { digest, .. }) => digests.push(digest.clone()),
         }
     }
 
-    let request = FindMissingBlobsRequest {
-        instance_name: instance_name(),
-        blob_digests: digests,
+    let missing_digests: HashSet<String> = match client.find_missing_blobs(digests).await {
+        Ok(missing) => missing.into_iter().map(|d| d.hash).collect(),
+        // uploading the blobs already in CAS again is harmless, skipping the
+        // ones that are not would leave the tree incomplete
+        Err(e) => {
+            warn!("failed to find missing blobs, uploading all of them {}", e);
+            stats.checked(&pending, &pending, start.elapsed());
+            return pending;
+        }
     };
 
-    let resp = bt_client.find_missing_blobs(request).await;
-    if resp.is_err() {
-        println!("failed to find missing blobs {}", resp.unwrap_err());
-        return vec![];
-    }
-    let resp = resp.unwrap();
-
-    let mut missing_digests = HashSet::new();
-    for d in &resp.get_ref().missing_blob_digests {
-        missing_digests.insert(d.hash.clone());
-    }
-
-    println!(
+    debug!(
         "find {}/{} missing digests",
         missing_digests.len(),
         pending.len()
     );
 
-    pending
-        .into_iter()
+    let missing: Vec<_> = pending
+        .iter()
         .filter(|e| {
             let d = match e {
                 WriteTask::WriteBlob(WriteBlob { digest, .. }) => digest,
@@ -219,27 +351,23 @@ async fn find_missing_blobs(bt_client: &mut CasClient, pending: Vec<WriteTask>)
             };
             missing_digests.contains(&d.hash)
         })
-        .collect()
+        .cloned()
+        .collect();
+    stats.checked(&pending, &missing, start.elapsed());
+    missing
 }
 
-/// The default max is 4MB
-/// use 3MB to account for protocol overhead
-const GRPC_MAX_MESSGE_SIZE: i64 = 3 * 1024 * 1024;
-
-/// Limit the batch size to 2000 to avoid BatchUpdateBlob bug:
-const MAX_PENDING_REQUIEST_COUNT: usize = 2000;
-
-async fn receiver_loop(mut recv: mpsc::Receiver<WriteTask>) {
-    // bytesteam client is used for streaming large objects
-    let mut bs_client = create_bs_client().await.unwrap();
-
-    // batch client is used for batching small objects
-    let mut bt_client = create_cas_client().await.unwrap();
-
-    let mut pending = vec![];
-    let mut pending_size: i64 = 0;
-    let mut ready = vec![];
-
+async fn receiver_loop(
+    mut recv: mpsc::Receiver<WriteTask>,
+    client: AsyncClient,
+    stats: Arc<UploadStats>,
+) {
+    // blobs larger than the batch limit of the server are streamed
+    let mut planner = BatchPlanner::new(client.capabilities().max_batch_size(), MAX_BATCH_COUNT);
+    let uploader = Uploader::new(client, stats.clone());
+
+    // the next task is only received once the uploads in flight leave room
+    // for it, so the senders are blocked by the full channel meanwhile
     while let Some(task) = recv.recv().await {
         match task {
             WriteTask::WriteFile(w) => {
@@ -248,6 +376,7 @@ async fn receiver_loop(mut recv: mpsc::Receiver<WriteTask>) {
                 // could be treated transparently
                 let file = File::open(w.path.clone()).await;
                 if file.is_err() {
+                    stats.failed(1);
                     continue;
                 }
                 let mut file = file.unwrap();
@@ -257,7 +386,8 @@ async fn receiver_loop(mut recv: mpsc::Receiver<WriteTask>) {
                 let mut buff = [0; 256];
                 let res = file.read(&mut buff).await;
                 if res.is_err() {
-                    println!("Failed to read the file {}", res.unwrap_err());
+                    warn!("Failed to read the file {}", res.unwrap_err());
+                    stats.failed(1);
                     continue;
                 }
 
@@ -267,22 +397,24 @@ async fn receiver_loop(mut recv: mpsc::Receiver<WriteTask>) {
                     let git_root = get_git_root(&parent_dir).unwrap();
                     let obj_path = get_lfs_object_path(git_root.clone(), lfs_file.hash.clone());
                     if !obj_path.exists() {
-                        println!("digest {:?} is missing", w.digest);
+                        info!("digest {:?} is missing", w.digest);
                         // git lfs fetch -I requires relative path
                         let rel_path = w.path.strip_prefix(git_root.clone());
                         if rel_path.is_err() {
-                            println!(
+                            warn!(
                                 "failed to strip prefix {:?} {:?} {:?}",
                                 w.path,
                                 git_root.clone(),
                                 rel_path.unwrap_err()
                             );
+                            stats.failed(1);
                             continue;
                         }
                         let rel_path = rel_path.unwrap();
                         let res = git_lfs_fetch(&git_root, &rel_path);
                         if res.is_err() {
-                            println!("failed to fetch lfs object {}", res.unwrap_err());
+                            warn!("failed to fetch lfs object {}", res.unwrap_err());
+                            stats.failed(1);
                             continue;
                         }
                     }
@@ -290,98 +422,52 @@ async fn receiver_loop(mut recv: mpsc::Receiver<WriteTask>) {
                 }
 
                 // stream the large file out directly
-                if w.digest.size_bytes > GRPC_MAX_MESSGE_SIZE {
-                    bs_write_file(&mut bs_client, &w.digest, path).await;
+                if !planner.fits(w.digest.size_bytes) {
+                    uploader.write_file(w.digest, path).await;
                 } else {
                     // read small files into memory
                     let file = File::open(path).await;
                     if file.is_err() {
+                        stats.failed(1);
                         continue;
                     }
                     let mut file = file.unwrap();
                     let mut buff = vec![];
                     let res = file.read_to_end(&mut buff).await;
                     if res.is_err() {
-                        println!("Failed to read the file {}", res.unwrap_err());
-                    } else {
-                        if pending_size + w.digest.size_bytes >= GRPC_MAX_MESSGE_SIZE
-                            || pending.len() > MAX_PENDING_REQUIEST_COUNT
-                        {
-                            ready.append(&mut pending);
-                            pending_size = w.digest.size_bytes;
-                        } else {
-                            pending_size += w.digest.size_bytes;
-                        }
-                        pending.push(WriteBlob {
-                            digest: w.digest,
-                            buff: buff,
-                        });
+                        warn!("Failed to read the file {}", res.unwrap_err());
+                        stats.failed(1);
+                        continue;
+                    }
+                    let size = w.digest.size_bytes;
+                    let blob = WriteBlob {
+                        digest: w.digest,
+                        buff,
+                    };
+                    if let Some(batch) = planner.push(blob, size) {
+                        uploader.batch_update(batch).await;
                     }
                 }
             }
             WriteTask::WriteBlob(w) => {
                 // stream out the large blob directly
-                if w.digest.size_bytes > GRPC_MAX_MESSGE_SIZE {
-                    bs_write_blob(&mut bs_client, &w.digest, w.buff).await;
+                if !planner.fits(w.digest.size_bytes) {
+                    uploader.write_blob(w.digest, w.buff).await;
                 } else {
-                    if pending_size + w.digest.size_bytes >= GRPC_MAX_MESSGE_SIZE
-                        || pending.len() > MAX_PENDING_REQUIEST_COUNT
-                    {
-                        ready.append(&mut pending);
-                        pending_size = w.digest.size_bytes;
-                    } else {
-                        pending_size += w.digest.size_bytes;
+                    let size = w.digest.size_bytes;
+                    if let Some(batch) = planner.push(w, size) {
+                        uploader.batch_update(batch).await;
                     }
-                    pending.push(WriteBlob {
-                        digest: w.digest,
-                        buff: w.buff,
-                    });
                 }
             }
         }
-
-        if !ready.is_empty() {
-            //println!("batching {} requests", ready.len());
-            let mut requests = vec![];
-            for t in ready {
-                requests.push(batch_update_blobs_request::Request {
-                    digest: Some(t.digest),
-                    data: t.buff,
-                    compressor: 0,
-                });
-            }
-            let request = BatchUpdateBlobsRequest {
-                instance_name: instance_name(),
-                requests: requests,
-            };
-            let resp = bt_client.batch_update_blobs(request).await;
-            if resp.is_err() {
-                println!("failed to batch upload {}", resp.unwrap_err());
-            }
-            ready = vec![];
-        }
     }
 
     // batch send the final remaining blobs before receiver exits
-    if !pending.is_empty() {
-        //println!("final batch {} requests", pending.len());
-        for t in pending {
-            let mut requests = vec![];
-            requests.push(batch_update_blobs_request::Request {
-                digest: Some(t.digest),
-                data: t.buff,
-                compressor: 0,
-            });
-            let request = BatchUpdateBlobsRequest {
-                instance_name: instance_name(),
-                requests: requests,
-            };
-            let resp = bt_client.batch_update_blobs(request).await;
-            if resp.is_err() {
-                println!("failed to batch upload {}", resp.unwrap_err());
-            }
-        }
+    if let Some(batch) = planner.flush() {
+        uploader.batch_update(batch).await;
     }
+    uploader.wait().await;
     //println!("receiver done");
 
     // Once all senders have gone out of scope,
@@ -390,6 +476,125 @@ async fn receiver_loop(mut recv: mpsc::Receiver<WriteTask>) {
     // thread.
 }
 
+/// bytes of a permit of the in-flight bytes semaphore, the permits are
+/// counted in KiB so that a single blob fits in the u32 permits
+const INFLIGHT_PERMIT_SIZE: u64 = 1024;
+
+/// Uploader runs the uploads of the receiver loop concurrently, bounded
+/// by the number of streams, the number of batch requests and the bytes
+/// in flight
+struct Uploader {
+    client: AsyncClient,
+    stats: Arc<UploadStats>,
+    options: UploadOptions,
+    streams: Arc<Semaphore>,
+    batches: Arc<Semaphore>,
+    inflight: Arc<Semaphore>,
+    inflight_permits: u32,
+}
+
+impl Uploader {
+    fn new(client: AsyncClient, stats: Arc<UploadStats>) -> Uploader {
+        let options = client.options().upload;
+        let options = UploadOptions {
+            streams: options.streams.max(1),
+            batches: options.batches.max(1),
+            ..options
+        };
+        let inflight_permits = cmp::min(
+            options.max_inflight_bytes / INFLIGHT_PERMIT_SIZE,
+            u32::MAX as u64,
+        )
+        .max(1) as u32;
+        Uploader {
+            client,
+            stats,
+            options,
+            streams: Arc::new(Semaphore::new(options.streams)),
+            batches: Arc::new(Semaphore::new(options.batches)),
+            inflight: Arc::new(Semaphore::new(inflight_permits as usize)),
+            inflight_permits,
+        }
+    }
+
+    /// wait until the bytes in flight leave room for `size` more bytes. A
+    /// blob larger than the bound waits for all of the others to finish
+    async fn reserve(&self, size: i64) -> OwnedSemaphorePermit {
+        let permits = (size.max(0) as u64 + INFLIGHT_PERMIT_SIZE - 1) / INFLIGHT_PERMIT_SIZE;
+        let permits = cmp::min(permits, self.inflight_permits as u64).max(1) as u32;
+        self.inflight
+            .clone()
+            .acquire_many_owned(permits)
+            .await
+            .unwrap()
+    }
+
+    async fn write_file(&self, digest: Digest, path: PathBuf) {
+        let bytes = self.reserve(digest.size_bytes).await;
+        let stream = self.streams.clone().acquire_owned().await.unwrap();
+        let client = self.client.clone();
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            match client.write_file(&digest, &path).await {
+                Ok(()) => stats.uploaded(1, digest.size_bytes, start),
+                Err(e) => {
+                    error!("failed to upload {:?}: {}", digest, e);
+                    stats.failed(1);
+                }
+            }
+            drop((stream, bytes));
+        });
+    }
+
+    async fn write_blob(&self, digest: Digest, buff: Vec<u8>) {
+        let bytes = self.reserve(digest.size_bytes).await;
+        let stream = self.streams.clone().acquire_owned().await.unwrap();
+        let client = self.client.clone();
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            match client.write_blob(&digest, buff).await {
+                Ok(()) => stats.uploaded(1, digest.size_bytes, start),
+                Err(e) => {
+                    error!("failed to upload {:?}: {}", digest, e);
+                    stats.failed(1);
+                }
+            }
+            drop((stream, bytes));
+        });
+    }
+
+    async fn batch_update(&self, blobs: Vec<WriteBlob>) {
+        let size = blobs.iter().map(|b| b.digest.size_bytes).sum();
+        let bytes = self.reserve(size).await;
+        let batch = self.batches.clone().acquire_owned().await.unwrap();
+        let client = self.client.clone();
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let count = blobs.len() as u64;
+            let blobs = blobs.into_iter().map(|b| (b.digest, b.buff)).collect();
+            // the blobs of a failed batch are all counted as failed, the
+            // response does not tell which of them made it
+            match client.batch_update_blobs(blobs).await {
+                Ok(()) => stats.uploaded(count, size, start),
+                Err(e) => {
+                    error!("failed to batch upload {}", e);
+                    stats.failed(count);
+                }
+            }
+            drop((batch, bytes));
+        });
+    }
+
+    /// wait for the uploads in flight to finish
+    async fn wait(&self) {
+        let _ = self.streams.acquire_many(self.options.streams as u32).await;
+        let _ = self.batches.acquire_many(self.options.batches as u32).await;
+    }
+}
+
 impl NonBlockingClient {
     pub fn new(send: mpsc::Sender<WriteTask>) -> Result<NonBlockingClient> {
         Ok(Self { sender: send })
@@ -401,7 +606,7 @@ impl NonBlockingClient {
                 digest: digest.clone(),
                 buff: buff,
             }))
-            .map_err(|e| anyhow::Error::msg(format!("failed to send {:?}", e)))
+            .map_err(upload_queue_closed)
     }
 
     /// writes a large file into CAS
@@ -413,266 +618,586 @@ impl NonBlockingClient {
                 digest: digest.clone(),
                 path,
             }))
-            .map_err(|e| anyhow::Error::msg(format!("failed to send {:?}", e)))
+            .map_err(upload_queue_closed)
     }
 }
 
+/// the receiver is gone, eg. it failed to connect to the server
+fn upload_queue_closed<T>(e: mpsc::error::SendError<T>) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        format!("failed to send {}", e),
+    ))
+}
+
+/// blobs larger than the chunk size are fetched and cached by chunks
+/// of this size so that reading a part of a large file does not need
+/// to fetch the whole blob
+pub const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
 /// CacheClient provide a CAS client interface with caching
+///
+/// Clones share the same connection and the same cache, so that a
+/// clone could be handed over to another thread
+#[derive(Clone)]
 pub struct CacheClient {
-    cas_client: BsClient,
+    /// swapped on reconnect, see [`CacheClient::reconnect`]
+    client: Arc<RwLock<AsyncClient>>,
 
-    rt: Runtime,
+    rt: Arc<Runtime>,
 
     /// simple but unbounded in memory cache
-    cache: HashMap<String, Vec<u8>>,
+    cache: Arc<Mutex<HashMap<String, Arc<Vec<u8>>>>>,
+
+    /// decoded directories so that listing a directory does not decode it again
+    dirs: Arc<Mutex<HashMap<String, Arc<Directory>>>>,
+
+    counters: Arc<CacheCounters>,
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    /// total size of the cached blobs, kept aside so that the
+    /// prefetcher could check the memory budget without the cache lock
+    bytes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+/// the counters are also exported as the process wide metrics, see [`metrics`]
+impl CacheCounters {
+    fn hit(&self, n: u64) {
+        self.hits.fetch_add(n, Ordering::Relaxed);
+        metrics::CACHE_HITS.inc_by(n);
+    }
+
+    fn miss(&self, n: u64) {
+        self.misses.fetch_add(n, Ordering::Relaxed);
+        metrics::CACHE_MISSES.inc_by(n);
+    }
+
+    fn start_fetch(&self, n: u64) {
+        self.in_flight.fetch_add(n, Ordering::Relaxed);
+        metrics::CACHE_IN_FLIGHT.add(n as i64);
+    }
+
+    fn end_fetch(&self, n: u64) {
+        self.in_flight.fetch_sub(n, Ordering::Relaxed);
+        metrics::CACHE_IN_FLIGHT.sub(n as i64);
+    }
+}
+
+/// CacheStats is a snapshot of the cache usage
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// number of blobs in the cache
+    pub blobs: u64,
+    /// total size of the blobs in the cache
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// number of blobs being fetched from CAS
+    pub in_flight: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
 }
 
 impl CacheClient {
     pub fn new() -> Result<CacheClient> {
-        let rt = tokio::runtime::Builder::new_current_thread()
+        CacheClient::with_options(ClientOptions::default())
+    }
+
+    pub fn with_options(options: ClientOptions) -> Result<CacheClient> {
+        // multi thread runtime so that clones could block on it from different threads
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
             .enable_all()
             .build()?;
-        let cas_client = rt.block_on(create_bs_client())?;
+        let client = rt.block_on(ClientBuilder::new().options(options).connect())?;
 
         Ok(CacheClient {
-            cache: HashMap::new(),
-            cas_client,
-            rt,
+            client: Arc::new(RwLock::new(client)),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            dirs: Arc::new(Mutex::new(HashMap::new())),
+            counters: Arc::new(CacheCounters::default()),
+            rt: Arc::new(rt),
         })
     }
 
-    /// get_tree is NOT cached atm
-    // pub fn get_tree(&mut self, hash: &str, size: i64) -> Result<Vec<Directory>> {
-    //     self.inner.get_tree(hash, size)
-    // }
+    /// fetch the tree under the directory with GetTree, up to `max_dirs`
+    /// directories, and cache all of them in one go instead of one round
+    /// trip per directory. Returns the number of directories being cached
+    pub fn preload_tree(&self, hash: &str, size: i64, max_dirs: usize) -> Result<usize> {
+        let dirs = self
+            .rt
+            .block_on(self.client().get_tree(&digest(hash, size), max_dirs))?;
+        let count = dirs.len();
+        for dir in dirs {
+            self.insert_dir(dir);
+        }
+        Ok(count)
+    }
+
+    /// whether the directory is decoded and cached
+    pub fn contains_dir(&self, hash: &str) -> bool {
+        self.dirs.lock().unwrap().contains_key(hash)
+    }
+
+    /// cache the directory under the digest of its encoding. GetTree does not
+    /// return the digests, directories stored in a non canonical encoding
+    /// are cached under a digest nobody asks for and fetched again on read
+    fn insert_dir(&self, dir: Directory) {
+        let buff = dir.encode_to_vec();
+        let hash = self.digest_function().hash(&buff);
+        let len = buff.len() as u64;
+        if self
+            .cache
+            .lock()
+            .unwrap()
+            .insert(hash.clone(), Arc::new(buff))
+            .is_none()
+        {
+            self.counters.bytes.fetch_add(len, Ordering::Relaxed);
+        }
+        self.dirs.lock().unwrap().insert(hash, Arc::new(dir));
+    }
 
-    // read_blob returns the reference to the memeory of the blob
+    // read_blob returns a shared reference to the memory of the blob
     // need to avoid memory copy since we need to make it performance for
     // large files
-    pub fn read_blob(&mut self, hash: &str, size: i64) -> Result<&Vec<u8>> {
-        // rust only allow put mutable reference before immutable reference
-        // see rustc --explain E0502
-        // mutable ref
-        if let Entry::Vacant(e) = self.cache.entry(hash.to_string()) {
-            let blob = self
-                .rt
-                .block_on(bs_read_blob(&mut self.cas_client, hash, size))?;
-            e.insert(blob);
-        };
-
-        // immutable ref
-        return Ok(self.cache.get(hash).unwrap());
+    pub fn read_blob(&self, hash: &str, size: i64) -> Result<Arc<Vec<u8>>> {
+        let digest = digest(hash, size);
+        let client = self.client();
+        self.fetch(hash, client.read_blob(&digest))
     }
 
-    pub fn get_dir(&mut self, hash: &str, size: i64) -> Result<Directory> {
-        let dir_bytes = self.read_blob(hash, size)?;
-        Directory::decode(&mut Cursor::new(dir_bytes)).map_err(|e| e.into())
+    /// read the `index`th chunk of the blob, see [`CHUNK_SIZE`]
+    pub fn read_chunk(&self, hash: &str, size: i64, index: u64) -> Result<Arc<Vec<u8>>> {
+        let digest = digest(hash, size);
+        let offset = index * CHUNK_SIZE;
+        let limit = CHUNK_SIZE.min((size as u64).saturating_sub(offset));
+        let client = self.client();
+        self.fetch(
+            &chunk_key(hash, index),
+            client.read_range(&digest, offset as i64, limit as i64),
+        )
     }
-}
 
-pub(crate) async fn create_channel() -> Result<Channel> {
-    //TODO: better system ca cert handling
-    //let ca_cert = tokio::fs::read("/etc/ssl/certs/ca-certificates.crt").await?;
-    let cas_endpoint = env::var("CAS_ENDPOINT")?;
-    let ca_cert_path = env::var("CA_CERT_PATH")?;
-    let ca_cert = tokio::fs::read(ca_cert_path).await?;
-    let ca_cert = Certificate::from_pem(ca_cert);
-    let tls = ClientTlsConfig::new().ca_certificate(ca_cert);
-    let channel = Channel::from_shared(cas_endpoint)?
-        .tls_config(tls)?
-        .connect()
-        .await?;
-
-    Ok(channel)
-}
+    /// read `len` bytes of the blob starting at `offset`. The blobs up to
+    /// [`CHUNK_SIZE`] are read as a whole, the larger ones by chunks
+    pub fn read_range(&self, hash: &str, size: i64, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let end = (offset + len).min(size as u64);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+        if size as u64 <= CHUNK_SIZE {
+            let blob = self.read_blob(hash, size)?;
+            return Ok(blob[offset as usize..end as usize].to_vec());
+        }
 
-pub(crate) async fn create_bs_client() -> Result<BsClient> {
-    let channel = create_channel().await?;
-    let interceptor = AuthInterceptor::new()?;
+        let mut content = Vec::with_capacity((end - offset) as usize);
+        for index in offset / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE {
+            let chunk = self.read_chunk(hash, size, index)?;
+            let chunk_start = index * CHUNK_SIZE;
+            let from = offset.max(chunk_start) - chunk_start;
+            let to = (end - chunk_start).min(chunk.len() as u64);
+            content.extend_from_slice(&chunk[from as usize..to as usize]);
+        }
+        Ok(content)
+    }
 
-    Ok(ByteStreamClient::with_interceptor(channel, interceptor))
-}
+    /// read `limit` bytes of the blob starting at `offset` with a single
+    /// ByteStream read bypassing the cache, eg. to stream a file once
+    pub fn fetch_range(&self, hash: &str, size: i64, offset: u64, limit: u64) -> Result<Vec<u8>> {
+        self.rt.block_on(
+            self.client()
+                .read_range(&digest(hash, size), offset as i64, limit as i64),
+        )
+    }
 
-pub(crate) async fn create_cas_client() -> Result<CasClient> {
-    let channel = create_channel().await?;
-    let interceptor = AuthInterceptor::new()?;
+    /// read many small blobs in batches, returns the contents keyed by hash.
+    /// Cached blobs are not fetched again
+    pub fn read_blobs(&self, digests: &[Digest]) -> Result<HashMap<String, Arc<Vec<u8>>>> {
+        let mut blobs = HashMap::new();
+        let mut missing = vec![];
+        {
+            let cache = self.cache.lock().unwrap();
+            for d in digests {
+                match cache.get(&d.hash) {
+                    Some(blob) => {
+                        blobs.insert(d.hash.clone(), blob.clone());
+                    }
+                    None => missing.push(d.clone()),
+                }
+            }
+        }
+        self.counters.hit(blobs.len() as u64);
+        if missing.is_empty() {
+            return Ok(blobs);
+        }
+        self.counters.miss(missing.len() as u64);
+
+        self.counters.start_fetch(missing.len() as u64);
+        let fetched = self.rt.block_on(self.client().batch_read_blobs(&missing));
+        self.counters.end_fetch(missing.len() as u64);
+
+        let mut cache = self.cache.lock().unwrap();
+        for (hash, blob) in fetched? {
+            let blob = Arc::new(blob);
+            if cache.insert(hash.clone(), blob.clone()).is_none() {
+                self.counters
+                    .bytes
+                    .fetch_add(blob.len() as u64, Ordering::Relaxed);
+            }
+            blobs.insert(hash, blob);
+        }
+        Ok(blobs)
+    }
 
-    Ok(ContentAddressableStorageClient::with_interceptor(
-        channel,
-        interceptor,
-    ))
-}
+    /// the capabilities of the server fetched on connect
+    pub fn capabilities(&self) -> Capabilities {
+        self.client.read().unwrap().capabilities().clone()
+    }
 
-//TODO: handle pagination
-pub(crate) async fn read_directories(
-    client: &mut CasClient,
-    hash: &str,
-    size: i64,
-) -> Result<Vec<Directory>> {
-    println!("read_directories {} {}", hash, size);
-    let request = GetTreeRequest {
-        instance_name: instance_name(),
-        root_digest: Some(Digest {
-            hash: hash.to_string(),
-            size_bytes: size,
-        }),
-        page_size: 16,
-        page_token: String::from(""),
-    };
-    let mut resp = client.get_tree(request).await?;
-    let stream = resp.get_mut();
+    /// the options the client was created with
+    pub fn options(&self) -> ClientOptions {
+        self.client.read().unwrap().options()
+    }
 
-    let message = stream.message().await?;
-    Ok(message.map_or(vec![], |resp| resp.directories))
-}
+    /// the hash function of the digests agreed with the server
+    pub fn digest_function(&self) -> DigestFunction {
+        self.client.read().unwrap().digest_function()
+    }
 
-/// Read the small blobs in batch. Do not use!
-/// This API is bugged:
-pub(crate) async fn batch_read_blob(
-    client: &mut CasClient,
-    hash: &str,
-    size: i64,
-) -> Result<Vec<u8>> {
-    println!("batch_read_blob {} {}", hash, size);
-    let request = BatchReadBlobsRequest {
-        instance_name: instance_name(),
-        digests: vec![Digest {
-            hash: hash.to_string(),
-            size_bytes: size,
-        }],
-        acceptable_compressors: vec![],
-    };
+    /// the async client underneath, eg. to share the connection with async code
+    pub fn client(&self) -> AsyncClient {
+        self.client.read().unwrap().clone()
+    }
 
-    let resp = client.batch_read_blobs(request).await?;
-    let resp = resp.get_ref();
+    /// connect again with the CA certificate and the token read again, eg.
+    /// once they are rotated. The cache and the digest function are kept,
+    /// the reads already started finish on the previous connection
+    pub fn reconnect(&self) -> Result<()> {
+        let options = ClientOptions {
+            digest_function: Some(self.digest_function()),
+            ..self.options()
+        };
+        let client = self
+            .rt
+            .block_on(ClientBuilder::new().options(options).connect())?;
+        *self.client.write().unwrap() = client;
+        Ok(())
+    }
 
-    Ok(resp.responses[0].data.clone())
-}
+    /// whether the whole blob is in the cache
+    pub fn contains(&self, hash: &str) -> bool {
+        self.cache.lock().unwrap().contains_key(hash)
+    }
 
-pub(crate) async fn bs_read_blob(client: &mut BsClient, hash: &str, size: i64) -> Result<Vec<u8>> {
-    //println!("bs_read_blob {} {}", hash, size);
-    let instance_name = instance_name();
-    let resource_name = format!("{}/blobs/{}/{}", instance_name, hash, size);
-    let request = ReadRequest {
-        resource_name: resource_name,
-        read_offset: 0,
-        read_limit: 0,
-    };
+    /// whether the `index`th chunk of the blob is in the cache
+    pub fn contains_chunk(&self, hash: &str, index: u64) -> bool {
+        self.cache
+            .lock()
+            .unwrap()
+            .contains_key(&chunk_key(hash, index))
+    }
 
-    let mut resp = client.read(request).await?;
-    let stream = resp.get_mut();
+    /// total size of the cached blobs
+    pub fn cached_bytes(&self) -> u64 {
+        self.counters.bytes.load(Ordering::Relaxed)
+    }
 
-    let mut content = vec![];
-    loop {
-        match stream.message().await? {
-            Some(mut message) => content.append(&mut message.data),
-            None => break,
+    fn fetch<F>(&self, key: &str, read: F) -> Result<Arc<Vec<u8>>>
+    where
+        F: std::future::Future<Output = Result<Vec<u8>>>,
+    {
+        if let Some(blob) = self.cache.lock().unwrap().get(key) {
+            self.counters.hit(1);
+            return Ok(blob.clone());
         }
+        self.counters.miss(1);
+
+        // the cache lock is not held while fetching so that other
+        // readers are not blocked by a slow fetch
+        self.counters.start_fetch(1);
+        let blob = self.rt.block_on(read);
+        self.counters.end_fetch(1);
+
+        let blob = Arc::new(blob?);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.insert(key.to_string(), blob.clone()).is_none() {
+            self.counters
+                .bytes
+                .fetch_add(blob.len() as u64, Ordering::Relaxed);
+        }
+        Ok(blob)
     }
-    Ok(content)
-}
 
-// resource_name includes digests this means the digest has to
-// be calculated before uploading the blob this also means we
-// cannot compute the hash and upload the blob at the same time
-pub(crate) async fn bs_write_blob(
-    client: &mut BsClient,
-    digest: &Digest,
-    buff: Vec<u8>,
-) -> Result<()> {
-    println!("write_blob: {:?}", digest);
-    let stream = WriteRequestStream::new(Cursor::new(buff), digest);
+    pub fn get_dir(&self, hash: &str, size: i64) -> Result<Directory> {
+        let dir_bytes = self.read_blob(hash, size)?;
+        Directory::decode(&mut Cursor::new(dir_bytes.as_slice()))
+            .map_err(|e| Error::corrupted(&digest(hash, size), e.to_string()))
+    }
 
-    bs_write(client, stream).await
-}
+    /// same as get_dir but the decoded directory is cached and shared
+    pub fn read_dir(&self, hash: &str, size: i64) -> Result<Arc<Directory>> {
+        if let Some(dir) = self.dirs.lock().unwrap().get(hash) {
+            return Ok(dir.clone());
+        }
+        let dir = Arc::new(self.get_dir(hash, size)?);
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), dir.clone());
+        Ok(dir)
+    }
 
-pub(crate) async fn bs_write_file(
-    client: &mut BsClient,
-    digest: &Digest,
-    path: PathBuf,
-) -> Result<()> {
-    println!("write_file: {:?} path: {:?}", digest, path);
-    let f = File::open(path).await?;
-    let stream = WriteRequestStream::new(f, digest);
+    /// drop all the blobs from the cache and returns what was dropped
+    pub fn flush(&self) -> CacheStats {
+        let mut cache = self.cache.lock().unwrap();
+        let stats = CacheStats {
+            blobs: cache.len() as u64,
+            bytes: cache.values().map(|b| b.len() as u64).sum(),
+            ..Default::default()
+        };
+        cache.clear();
+        metrics::CACHE_EVICTIONS.inc_by(stats.blobs);
+        self.dirs.lock().unwrap().clear();
+        self.counters.bytes.store(0, Ordering::Relaxed);
+        stats
+    }
 
-    bs_write(client, stream).await
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.cache.lock().unwrap();
+        CacheStats {
+            blobs: cache.len() as u64,
+            bytes: cache.values().map(|b| b.len() as u64).sum(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            in_flight: self.counters.in_flight.load(Ordering::Relaxed),
+        }
+    }
 }
 
-async fn bs_write<T: AsyncRead + Send + Unpin + 'static>(
-    client: &mut BsClient,
-    stream: WriteRequestStream<T>,
-) -> Result<()> {
-    client
-        .write(stream)
-        .await
-        .map(|_v| ())
-        .map_err(|e| anyhow::Error::msg(format!("failed to write stream {}", e)))
+/// the cache key of the `index`th chunk of a blob
+pub fn chunk_key(hash: &str, index: u64) -> String {
+    format!("{}@{}", hash, index)
 }
 
-struct WriteRequestStream<T: AsyncRead + Send + Unpin> {
-    read: T,
-    resource_name: String,
-    offset: i64,
+fn digest(hash: &str, size: i64) -> Digest {
+    Digest {
+        hash: hash.to_string(),
+        size_bytes: size,
+    }
 }
 
-impl<T: AsyncRead + Send + Unpin> WriteRequestStream<T> {
-    fn new(read: T, digest: &Digest) -> WriteRequestStream<T> {
-        let instance_name = instance_name();
-        let uuid = Uuid::new_v4();
-        let resource_name = format!(
-            "{}/uploads/{}/blobs/{}/{}",
-            instance_name, uuid, digest.hash, digest.size_bytes
+#[cfg(test)]
+mod tests {
+    use super::super::fake::{FakeCas, Faults};
+    use super::*;
+    use tonic::Code;
+
+    fn sha256_digest(data: &[u8]) -> Digest {
+        digest(&DigestFunction::Sha256.hash(data), data.len() as i64)
+    }
+
+    #[test]
+    fn test_write_and_read_blobs() {
+        let cas = FakeCas::new();
+        let _server = cas.start().unwrap();
+
+        let small = b"hello world".to_vec();
+        let large: Vec<u8> = (0..2 * CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        let mut client = Client::new().unwrap();
+        for data in [&small, &large] {
+            client.write_blob(&sha256_digest(data), data).unwrap();
+        }
+        assert_eq!(cas.get(&sha256_digest(&large).hash), Some(large.clone()));
+
+        let cache = CacheClient::new().unwrap();
+        let d = sha256_digest(&small);
+        assert_eq!(*cache.read_blob(&d.hash, d.size_bytes).unwrap(), small);
+        let d = sha256_digest(&large);
+        let range = cache
+            .read_range(&d.hash, d.size_bytes, CHUNK_SIZE - 5, 10)
+            .unwrap();
+        assert_eq!(
+            range,
+            &large[CHUNK_SIZE as usize - 5..CHUNK_SIZE as usize + 5]
         );
-        WriteRequestStream {
-            read: read,
-            resource_name: resource_name,
-            offset: 0,
+    }
+
+    #[test]
+    fn test_read_blobs_falls_back_on_batch_errors() {
+        let cas = FakeCas::new();
+        let a = cas.insert(b"a".to_vec());
+        let b = cas.insert(b"b".to_vec());
+        let mut faults = Faults::default();
+        faults.batch_errors.insert(b.hash.clone(), Code::Internal);
+        cas.set_faults(faults);
+        let _server = cas.start().unwrap();
+
+        let cache = CacheClient::new().unwrap();
+        let blobs = cache.read_blobs(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!(*blobs[&a.hash], b"a".to_vec());
+        assert_eq!(*blobs[&b.hash], b"b".to_vec());
+        assert_eq!(cas.calls("BatchReadBlobs"), 1);
+        assert_eq!(cas.calls("Read"), 1);
+    }
+
+    #[test]
+    fn test_dropped_read_is_not_cached() {
+        let cas = FakeCas::new();
+        let data = vec![7; 256 * 1024];
+        let d = cas.insert(data.clone());
+        let mut faults = Faults::default();
+        faults.drop_stream.insert(d.hash.clone());
+        cas.set_faults(faults);
+        let _server = cas.start().unwrap();
+
+        let cache = CacheClient::new().unwrap();
+        assert!(cache.read_blob(&d.hash, d.size_bytes).is_err());
+        assert!(!cache.contains(&d.hash));
+
+        cas.set_faults(Faults::default());
+        assert_eq!(*cache.read_blob(&d.hash, d.size_bytes).unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let cas = FakeCas::new();
+        let d = cas.insert(b"hello".to_vec());
+        let mut faults = Faults::default();
+        faults.wrong_bytes.insert(d.hash.clone());
+        cas.set_faults(faults);
+        let _server = cas.start().unwrap();
+
+        let cache = CacheClient::new().unwrap();
+        match cache.read_blob(&d.hash, d.size_bytes) {
+            Err(Error::Corrupted { digest, .. }) => assert_eq!(digest, d),
+            res => panic!("unexpected result {:?}", res),
         }
+        assert!(!cache.contains(&d.hash));
+
+        // the batch read falls back to ByteStream which fails the same way
+        match cache.read_blobs(&[d.clone()]) {
+            Err(Error::Corrupted { digest, .. }) => assert_eq!(digest, d),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let missing = sha256_digest(b"missing");
+        let e = cache
+            .read_blob(&missing.hash, missing.size_bytes)
+            .unwrap_err();
+        assert!(e.is_not_found());
+        assert_eq!(e.digest(), Some(&missing));
     }
-}
 
-impl<T: AsyncRead + Send + Unpin> Stream for WriteRequestStream<T> {
-    type Item = WriteRequest;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut buff = [0; 16 * 1024];
-        let mut read_buff = ReadBuf::new(&mut buff);
-        match Pin::new(&mut self.read).poll_read(cx, &mut read_buff) {
-            Poll::Ready(_) => {
-                let buff = read_buff.filled();
-                let size = buff.len() as i64;
-                // println!(
-                //     "{} size: {} offset: {}",
-                //     self.resource_name.clone(),
-                //     size,
-                //     self.offset
-                // );
-                self.offset += size;
-                if size == 0 {
-                    Poll::Ready(None)
-                } else {
-                    Poll::Ready(Some(WriteRequest {
-                        resource_name: self.resource_name.clone(),
-                        write_offset: self.offset,
-                        finish_write: size == 0,
-                        data: buff.to_vec(),
-                    }))
-                }
-            }
-            //TODO: when this happen?
-            // Poll::Ready(Err(e)) => {
-            // println!("Failed to read stream {}", e);
-            // Poll::Pending
-            // }
-            Poll::Pending => Poll::Pending,
+    #[test]
+    fn test_upload_tail_in_one_batch() {
+        let cas = FakeCas::new();
+        cas.set_faults(Faults {
+            delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+        let _server = cas.start().unwrap();
+
+        let stats = Arc::new(UploadStats::default());
+        let (send, handle) = spawn_receiver(ClientOptions::default(), stats.clone()).unwrap();
+        let client = NonBlockingClient::new(send).unwrap();
+        for i in 0..10 {
+            let data = format!("blob {}", i).into_bytes();
+            client.write_blob(&sha256_digest(&data), data).unwrap();
         }
+        drop(client);
+        handle.join().unwrap();
+
+        assert_eq!(cas.len(), 10);
+        assert_eq!(cas.calls("BatchUpdateBlobs"), 1);
+        let counts = stats.snapshot();
+        assert_eq!(counts.checked_blobs, 10);
+        assert_eq!(counts.missing_blobs, 10);
+        assert_eq!(counts.uploaded_blobs, 10);
+        assert_eq!(counts.uploaded_bytes, counts.missing_bytes);
+        assert_eq!(counts.failed_blobs, 0);
+    }
+
+    #[test]
+    fn test_compressed_transfers() {
+        let cas = FakeCas::new();
+        cas.set_capabilities(ServerCapabilities {
+            cache_capabilities: Some(CacheCapabilities {
+                digest_functions: vec![digest_function::Value::Sha256 as i32],
+                supported_compressors: vec![compressor::Value::Zstd as i32],
+                supported_batch_update_compressors: vec![compressor::Value::Zstd as i32],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let _server = cas.start().unwrap();
+
+        let data = "hello world ".repeat(1000).into_bytes();
+        let d = sha256_digest(&data);
+        let mut client = Client::new().unwrap();
+        client.write_blob(&d, &data).unwrap();
+        assert_eq!(cas.get(&d.hash), Some(data.clone()));
+
+        let other = "hello cas ".repeat(1000).into_bytes();
+        let o = cas.insert(other.clone());
+        let cache = CacheClient::new().unwrap();
+        assert_eq!(*cache.read_blob(&d.hash, d.size_bytes).unwrap(), data);
+        assert_eq!(*cache.read_blobs(&[o.clone()]).unwrap()[&o.hash], other);
+    }
+
+    #[test]
+    fn test_preload_tree() {
+        let cas = FakeCas::new();
+        let leaf = cas.insert_dir(&Directory::default());
+        let root = cas.insert_dir(&Directory {
+            directories: vec![DirectoryNode {
+                name: String::from("a"),
+                digest: Some(leaf.clone()),
+            }],
+            ..Default::default()
+        });
+        let _server = cas.start().unwrap();
+
+        let cache = CacheClient::new().unwrap();
+        assert_eq!(
+            cache
+                .preload_tree(&root.hash, root.size_bytes, 100)
+                .unwrap(),
+            2
+        );
+        assert!(cache.contains_dir(&root.hash));
+        assert!(cache.contains_dir(&leaf.hash));
+        assert_eq!(cas.calls("Read"), 0);
     }
-}
 
-/// instance_name returns the instance name for the CAS client
-fn instance_name() -> String {
-    match env::var("INSTANCE_NAME") {
-        Ok(v) => v,
-        Err(_) => String::from(""),
+    #[test]
+    fn test_reconnect() {
+        let cas = FakeCas::new();
+        let a = cas.insert(b"hello".to_vec());
+        let b = cas.insert(b"world".to_vec());
+        let _server = cas.start().unwrap();
+
+        let cache = CacheClient::new().unwrap();
+        cache.read_blob(&a.hash, a.size_bytes).unwrap();
+        cache.reconnect().unwrap();
+
+        // the cache outlives the connection
+        assert_eq!(*cache.read_blob(&a.hash, a.size_bytes).unwrap(), b"hello");
+        assert_eq!(*cache.read_blob(&b.hash, b.size_bytes).unwrap(), b"world");
+        assert_eq!(cas.calls("Read"), 2);
+        assert_eq!(cas.calls("GetCapabilities"), 2);
     }
 }
@@ -0,0 +1,230 @@
+//! Capabilities of the remote CAS server.
+//!
+//! The capabilities are fetched once per connection and decide the batch
+//! size, the digest function and the compressors the client uses.
+use super::auth::AuthInterceptor;
+use super::error::Error;
+use crate::hash::DigestFunction;
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::capabilities_client::CapabilitiesClient;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
+    compressor, digest_function, GetCapabilitiesRequest, SemVer, ServerCapabilities,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use tonic::transport::Channel;
+
+/// The default max gRPC message size is 4MB
+/// use 3MB to account for protocol overhead
+pub const GRPC_MAX_MESSAGE_SIZE: i64 = 3 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// max total size of the blobs in a batch request, 0 when the server has no limit
+    pub max_batch_total_size_bytes: i64,
+
+    /// names of the digest functions, eg. SHA256
+    pub digest_functions: Vec<String>,
+
+    /// names of the compressors supported by ByteStream, eg. ZSTD
+    pub compressors: Vec<String>,
+
+    /// names of the compressors supported by BatchUpdateBlobs
+    pub batch_update_compressors: Vec<String>,
+
+    pub low_api_version: String,
+    pub high_api_version: String,
+}
+
+impl Default for Capabilities {
+    /// what the client assumes of the servers without the Capabilities service
+    fn default() -> Self {
+        Capabilities {
+            max_batch_total_size_bytes: 0,
+            digest_functions: vec![String::from("SHA256")],
+            compressors: vec![],
+            batch_update_compressors: vec![],
+            low_api_version: String::new(),
+            high_api_version: String::new(),
+        }
+    }
+}
+
+impl From<ServerCapabilities> for Capabilities {
+    fn from(caps: ServerCapabilities) -> Self {
+        let mut res = Capabilities {
+            low_api_version: caps.low_api_version.map(version).unwrap_or_default(),
+            high_api_version: caps.high_api_version.map(version).unwrap_or_default(),
+            ..Default::default()
+        };
+        if let Some(cache) = caps.cache_capabilities {
+            res.max_batch_total_size_bytes = cache.max_batch_total_size_bytes;
+            res.digest_functions = cache
+                .digest_functions
+                .into_iter()
+                .map(digest_function_name)
+                .collect();
+            res.compressors = cache
+                .supported_compressors
+                .into_iter()
+                .map(compressor_name)
+                .collect();
+            res.batch_update_compressors = cache
+                .supported_batch_update_compressors
+                .into_iter()
+                .map(compressor_name)
+                .collect();
+        }
+        res
+    }
+}
+
+impl Capabilities {
+    /// max total size of a batch request, bounded by the gRPC message size
+    pub fn max_batch_size(&self) -> i64 {
+        if self.max_batch_total_size_bytes > 0 {
+            cmp::min(self.max_batch_total_size_bytes, GRPC_MAX_MESSAGE_SIZE)
+        } else {
+            GRPC_MAX_MESSAGE_SIZE
+        }
+    }
+
+    /// servers that list no digest function predate the field and only know SHA256
+    pub fn supports_digest_function(&self, name: &str) -> bool {
+        if self.digest_functions.is_empty() {
+            return name == "SHA256";
+        }
+        self.digest_functions.iter().any(|f| f == name)
+    }
+
+    pub fn supports_compressor(&self, name: &str) -> bool {
+        self.compressors.iter().any(|c| c == name)
+    }
+
+    pub fn supports_batch_update_compressor(&self, name: &str) -> bool {
+        self.batch_update_compressors.iter().any(|c| c == name)
+    }
+
+    /// pick the digest function to talk to the server with. Without a
+    /// preference SHA256 is picked when supported, then BLAKE3
+    pub fn digest_function(&self, preferred: Option<DigestFunction>) -> Result<DigestFunction> {
+        let candidates = match preferred {
+            Some(f) => vec![f],
+            None => vec![DigestFunction::Sha256, DigestFunction::Blake3],
+        };
+        candidates
+            .into_iter()
+            .find(|f| self.supports_digest_function(f.name()))
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "no digest function in common with the server, supported: {:?}",
+                    self.digest_functions
+                ))
+            })
+    }
+}
+
+fn version(v: SemVer) -> String {
+    let mut res = format!("{}.{}.{}", v.major, v.minor, v.patch);
+    if !v.prerelease.is_empty() {
+        res.push('-');
+        res.push_str(&v.prerelease);
+    }
+    res
+}
+
+fn digest_function_name(value: i32) -> String {
+    match digest_function::Value::from_i32(value) {
+        Some(f) => format!("{:?}", f).to_uppercase(),
+        None => format!("UNKNOWN({})", value),
+    }
+}
+
+fn compressor_name(value: i32) -> String {
+    match compressor::Value::from_i32(value) {
+        Some(c) => format!("{:?}", c).to_uppercase(),
+        None => format!("UNKNOWN({})", value),
+    }
+}
+
+/// fetch the capabilities of the server. Servers without the Capabilities
+/// service are assumed to have the default capabilities
+pub(crate) async fn get_capabilities(
+    channel: Channel,
+    interceptor: AuthInterceptor,
+    instance_name: &str,
+) -> Result<Capabilities, Error> {
+    let mut client = CapabilitiesClient::with_interceptor(channel, interceptor);
+
+    let request = GetCapabilitiesRequest {
+        instance_name: instance_name.to_string(),
+    };
+    let caps = match client.get_capabilities(request).await {
+        Ok(resp) => Capabilities::from(resp.into_inner()),
+        Err(status) if status.code() == tonic::Code::Unimplemented => Capabilities::default(),
+        Err(status) => return Err(status.into()),
+    };
+    Ok(caps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_remote_apis_rs::build::bazel::remote::execution::v2::CacheCapabilities;
+
+    #[test]
+    fn test_from_server_capabilities() {
+        let caps = Capabilities::from(ServerCapabilities {
+            cache_capabilities: Some(CacheCapabilities {
+                digest_functions: vec![digest_function::Value::Sha256 as i32],
+                max_batch_total_size_bytes: 1024,
+                supported_compressors: vec![compressor::Value::Zstd as i32],
+                ..Default::default()
+            }),
+            high_api_version: Some(SemVer {
+                major: 2,
+                minor: 2,
+                patch: 0,
+                prerelease: String::new(),
+            }),
+            ..Default::default()
+        });
+        assert!(caps.supports_digest_function("SHA256"));
+        assert!(caps.supports_compressor("ZSTD"));
+        assert!(!caps.supports_batch_update_compressor("ZSTD"));
+        assert_eq!(caps.max_batch_size(), 1024);
+        assert_eq!(caps.high_api_version, "2.2.0");
+        assert_eq!(caps.digest_function(None).unwrap(), DigestFunction::Sha256);
+        assert!(caps.digest_function(Some(DigestFunction::Blake3)).is_err());
+    }
+
+    #[test]
+    fn test_default_capabilities() {
+        let caps = Capabilities::default();
+        assert_eq!(caps.max_batch_size(), GRPC_MAX_MESSAGE_SIZE);
+        assert_eq!(caps.digest_function(None).unwrap(), DigestFunction::Sha256);
+    }
+
+    #[test]
+    fn test_blake3_only_server() {
+        let caps = Capabilities {
+            digest_functions: vec![String::from("BLAKE3")],
+            ..Default::default()
+        };
+        assert_eq!(caps.digest_function(None).unwrap(), DigestFunction::Blake3);
+        assert!(caps.digest_function(Some(DigestFunction::Sha256)).is_err());
+    }
+
+    #[test]
+    fn test_blake3_among_others() {
+        let caps = Capabilities {
+            digest_functions: vec![String::from("SHA256"), String::from("BLAKE3")],
+            ..Default::default()
+        };
+        assert_eq!(caps.digest_function(None).unwrap(), DigestFunction::Sha256);
+        assert_eq!(
+            caps.digest_function(Some(DigestFunction::Blake3)).unwrap(),
+            DigestFunction::Blake3
+        );
+    }
+}
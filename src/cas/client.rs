@@ -0,0 +1,894 @@
+//! Async CAS client.
+//!
+//! Client talks to the ContentAddressableStorage, ByteStream and
+//! Capabilities services of the remote CAS with async methods, so that it
+//! can be embedded into an async service without nesting runtimes. The
+//! clients of [`super::blocking`] are thin wrappers over it.
+use super::auth::{read_token, AuthInterceptor};
+use super::batch::{BatchPlanner, MAX_BATCH_COUNT};
+use super::capabilities::{get_capabilities, Capabilities};
+use super::compression::{decompress, Codec, CompressionOptions};
+use super::error::{Error, Result};
+use crate::hash::DigestFunction;
+use crate::metrics;
+use async_compression::tokio::bufread::ZstdEncoder;
+use async_compression::Level;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::content_addressable_storage_client::*;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::*;
+use bazel_remote_apis_rs::google::bytestream::byte_stream_client::ByteStreamClient;
+use bazel_remote_apis_rs::google::bytestream::{ReadRequest, WriteRequest};
+use futures::Stream;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::future::Future;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio::io::{BufReader, ReadBuf};
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig};
+use tonic::Code;
+use tracing::{debug, instrument, warn};
+
+type CasClient = ContentAddressableStorageClient<InterceptedService<Channel, AuthInterceptor>>;
+
+type BsClient = ByteStreamClient<InterceptedService<Channel, AuthInterceptor>>;
+
+/// ClientOptions are the transfer options of the CAS clients
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientOptions {
+    pub compression: CompressionOptions,
+
+    /// None picks the digest function from the server capabilities
+    pub digest_function: Option<DigestFunction>,
+
+    pub upload: UploadOptions,
+}
+
+/// UploadOptions are the concurrency of the uploads
+#[derive(Debug, Clone, Copy)]
+pub struct UploadOptions {
+    /// max number of concurrent ByteStream writes
+    pub streams: usize,
+
+    /// max number of concurrent BatchUpdateBlobs requests
+    pub batches: usize,
+
+    /// max bytes of the blobs being uploaded, no more blobs are taken from
+    /// the upload queue until the uploads in flight finish
+    pub max_inflight_bytes: u64,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            streams: 8,
+            batches: 4,
+            max_inflight_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// agree on how the blobs are encoded on the wire with the server
+fn negotiate(options: &ClientOptions, caps: &Capabilities) -> Result<Codec> {
+    let mut codec =
+        Codec::negotiate(&options.compression, caps).map_err(|e| Error::config(e.to_string()))?;
+    codec.digest_function = caps
+        .digest_function(options.digest_function)
+        .map_err(|e| Error::config(e.to_string()))?;
+    Ok(codec)
+}
+
+/// ClientBuilder sets up the connection of a [`Client`]. The settings left
+/// out are taken from the environment: `CAS_ENDPOINT`, `CA_CERT_PATH`,
+/// `INSTANCE_NAME` and the token in `~/.rbe-auth-token`
+#[derive(Clone, Default)]
+pub struct ClientBuilder {
+    endpoint: Option<String>,
+    ca_cert_path: Option<PathBuf>,
+    token: Option<String>,
+    instance_name: Option<String>,
+    options: ClientOptions,
+}
+
+impl ClientBuilder {
+    pub fn new() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// the plaintext `http://` endpoints, eg. a local test server, are
+    /// connected without TLS and the token is never sent to them
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> ClientBuilder {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// the PEM encoded CA certificate of the TLS endpoints
+    pub fn ca_cert_path(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.ca_cert_path = Some(path.into());
+        self
+    }
+
+    /// the bearer token sent to the TLS endpoints
+    pub fn token(mut self, token: impl Into<String>) -> ClientBuilder {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn instance_name(mut self, instance_name: impl Into<String>) -> ClientBuilder {
+        self.instance_name = Some(instance_name.into());
+        self
+    }
+
+    pub fn options(mut self, options: ClientOptions) -> ClientBuilder {
+        self.options = options;
+        self
+    }
+
+    /// connect to the server, fetch its capabilities and agree on the
+    /// digest function and the compression
+    pub async fn connect(self) -> Result<Client> {
+        let endpoint = match self.endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                env::var("CAS_ENDPOINT").map_err(|_| Error::config("CAS_ENDPOINT is not set"))?
+            }
+        };
+        let plaintext = endpoint.starts_with("http://");
+        let channel = Channel::from_shared(endpoint)
+            .map_err(|e| Error::config(format!("invalid CAS endpoint: {}", e)))?;
+        let channel = if plaintext {
+            channel.connect().await?
+        } else {
+            //TODO: better system ca cert handling
+            let ca_cert_path = match self.ca_cert_path {
+                Some(path) => path,
+                None => PathBuf::from(
+                    env::var("CA_CERT_PATH")
+                        .map_err(|_| Error::config("CA_CERT_PATH is not set"))?,
+                ),
+            };
+            let ca_cert = Certificate::from_pem(tokio::fs::read(ca_cert_path).await?);
+            let tls = ClientTlsConfig::new().ca_certificate(ca_cert);
+            channel.tls_config(tls)?.connect().await?
+        };
+        let token = match self.token {
+            _ if plaintext => None,
+            Some(token) => Some(token),
+            None => Some(read_token()?),
+        };
+        let interceptor = AuthInterceptor::new(token);
+        let instance_name = self
+            .instance_name
+            .unwrap_or_else(|| env::var("INSTANCE_NAME").unwrap_or_default());
+
+        let caps = get_capabilities(channel.clone(), interceptor.clone(), &instance_name).await?;
+        let codec = negotiate(&self.options, &caps)?;
+        Ok(Client {
+            cas: ContentAddressableStorageClient::with_interceptor(
+                channel.clone(),
+                interceptor.clone(),
+            ),
+            bs: ByteStreamClient::with_interceptor(channel, interceptor),
+            caps,
+            codec,
+            options: self.options,
+            instance_name,
+        })
+    }
+}
+
+/// Client is the async CAS client.
+///
+/// Clones share the same connection, so that a clone could be moved into
+/// a spawned task
+#[derive(Clone)]
+pub struct Client {
+    /// CAS service client for the tree and the batch APIs
+    cas: CasClient,
+
+    /// ByteStream client for the blobs too large to batch
+    bs: BsClient,
+
+    caps: Capabilities,
+
+    codec: Codec,
+
+    options: ClientOptions,
+
+    instance_name: String,
+}
+
+impl Client {
+    /// connect with the settings from the environment, see [`ClientBuilder`]
+    pub async fn connect() -> Result<Client> {
+        ClientBuilder::new().connect().await
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// the capabilities of the server fetched on connect
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.caps
+    }
+
+    /// the options the client was created with
+    pub fn options(&self) -> ClientOptions {
+        self.options
+    }
+
+    /// the hash function of the digests agreed with the server
+    pub fn digest_function(&self) -> DigestFunction {
+        self.codec.digest_function
+    }
+
+    /// the `digest_function` field of the CAS requests, the ByteStream
+    /// requests carry it in the resource names instead
+    fn digest_function_value(&self) -> i32 {
+        match self.codec.digest_function {
+            DigestFunction::Sha256 => digest_function::Value::Sha256 as i32,
+            DigestFunction::Blake3 => digest_function::Value::Blake3 as i32,
+        }
+    }
+
+    pub fn instance_name(&self) -> &str {
+        &self.instance_name
+    }
+
+    /// get the content of a single blob with ByteStream
+    #[instrument(level = "debug", skip_all, fields(hash = %digest.hash, size = digest.size_bytes))]
+    pub async fn read_blob(&self, digest: &Digest) -> Result<Vec<u8>> {
+        let resource_name =
+            self.codec
+                .read_resource_name(&self.instance_name, &digest.hash, digest.size_bytes);
+        let content = self
+            .read(resource_name, 0, 0)
+            .await
+            .map_err(|e| e.with_digest(digest))?;
+        let content = if self.codec.bytestream {
+            decompress(&content, digest.size_bytes)
+                .map_err(|e| Error::corrupted(digest, e.to_string()))?
+        } else {
+            content
+        };
+        self.verify(digest, &content)?;
+        Ok(content)
+    }
+
+    /// read `limit` bytes of the blob starting at `offset`, a zero limit reads till the end.
+    /// Ranges are always read uncompressed since a part of a zstd frame cannot be decoded
+    #[instrument(
+        level = "debug",
+        skip(self, digest),
+        fields(hash = %digest.hash, size = digest.size_bytes)
+    )]
+    pub async fn read_range(&self, digest: &Digest, offset: i64, limit: i64) -> Result<Vec<u8>> {
+        let identity = Codec {
+            bytestream: false,
+            ..self.codec
+        };
+        let resource_name =
+            identity.read_resource_name(&self.instance_name, &digest.hash, digest.size_bytes);
+        self.read(resource_name, offset, limit)
+            .await
+            .map_err(|e| e.with_digest(digest))
+    }
+
+    /// read the blobs with as few BatchReadBlobs calls as possible, returns the
+    /// contents keyed by hash. Blobs too large to batch, or failed in the batch,
+    /// are read with ByteStream instead
+    #[instrument(level = "debug", skip_all, fields(blobs = digests.len()))]
+    pub async fn batch_read_blobs(&self, digests: &[Digest]) -> Result<HashMap<String, Vec<u8>>> {
+        let (batches, mut fallback) =
+            group_digests(digests, self.caps.max_batch_size(), MAX_BATCH_COUNT);
+
+        let mut blobs = HashMap::new();
+        for batch in batches {
+            debug!("batch read {} blobs", batch.len());
+            let request = BatchReadBlobsRequest {
+                instance_name: self.instance_name.clone(),
+                digests: batch.clone(),
+                acceptable_compressors: if self.codec.batch_read {
+                    vec![compressor::Value::Zstd as i32]
+                } else {
+                    vec![]
+                },
+                digest_function: self.digest_function_value(),
+            };
+            let resp = observe("BatchReadBlobs", async {
+                Ok(self.cas.clone().batch_read_blobs(request).await?)
+            })
+            .await?;
+
+            // map the responses back by digest, the order is not guaranteed
+            for r in resp.into_inner().responses {
+                metrics::CAS_READ_BYTES.inc_by(r.data.len() as u64);
+                let code = r.status.as_ref().map_or(0, |s| s.code);
+                let data = match &r.digest {
+                    Some(d) if code == 0 && r.compressor == compressor::Value::Zstd as i32 => {
+                        decompress(&r.data, d.size_bytes).ok()
+                    }
+                    Some(_) if code == 0 => Some(r.data),
+                    _ => None,
+                };
+                match (r.digest, data) {
+                    (Some(d), Some(data)) if self.verify(&d, &data).is_ok() => {
+                        blobs.insert(d.hash, data);
+                    }
+                    (Some(d), _) => warn!(
+                        hash = %d.hash,
+                        size = d.size_bytes,
+                        code,
+                        "failed to batch read"
+                    ),
+                    (None, _) => {}
+                }
+            }
+            for d in batch {
+                if !blobs.contains_key(&d.hash) {
+                    fallback.push(d);
+                }
+            }
+        }
+
+        for d in fallback {
+            let blob = self.read_blob(&d).await?;
+            blobs.insert(d.hash, blob);
+        }
+        Ok(blobs)
+    }
+
+    // resource_name includes digests this means the digest has to
+    // be calculated before uploading the blob this also means we
+    // cannot compute the hash and upload the blob at the same time
+    #[instrument(level = "debug", skip_all, fields(hash = %digest.hash, size = digest.size_bytes))]
+    pub async fn write_blob(&self, digest: &Digest, buff: Vec<u8>) -> Result<()> {
+        let resource_name =
+            self.codec
+                .write_resource_name(&self.instance_name, &digest.hash, digest.size_bytes);
+        let buff = if self.codec.bytestream {
+            self.codec.compress(&buff)?
+        } else {
+            buff
+        };
+        let stream = WriteRequestStream::new(Cursor::new(buff), resource_name);
+
+        self.write(digest, stream).await
+    }
+
+    /// writes a large file into CAS
+    #[instrument(
+        level = "debug",
+        skip(self, digest),
+        fields(hash = %digest.hash, size = digest.size_bytes)
+    )]
+    pub async fn write_file(&self, digest: &Digest, path: &Path) -> Result<()> {
+        let resource_name =
+            self.codec
+                .write_resource_name(&self.instance_name, &digest.hash, digest.size_bytes);
+        let f = File::open(path).await?;
+        if self.codec.bytestream {
+            // compress while streaming so that large files are not read into memory
+            let f = ZstdEncoder::with_quality(
+                BufReader::new(f),
+                Level::Precise(self.codec.level as u32),
+            );
+            return self
+                .write(digest, WriteRequestStream::new(f, resource_name))
+                .await;
+        }
+        let stream = WriteRequestStream::new(f, resource_name);
+
+        self.write(digest, stream).await
+    }
+
+    /// write the blobs with as few BatchUpdateBlobs calls as possible, the
+    /// blobs too large to batch are written with ByteStream instead. Returns
+    /// the error of the first blob failed to write
+    #[instrument(level = "debug", skip_all, fields(blobs = blobs.len()))]
+    pub async fn batch_update_blobs(&self, blobs: Vec<(Digest, Vec<u8>)>) -> Result<()> {
+        let mut planner = BatchPlanner::new(self.caps.max_batch_size(), MAX_BATCH_COUNT);
+        let mut batches = vec![];
+        for (digest, buff) in blobs {
+            if !planner.fits(digest.size_bytes) {
+                self.write_blob(&digest, buff).await?;
+                continue;
+            }
+            let size = digest.size_bytes;
+            batches.extend(planner.push((digest, buff), size));
+        }
+        batches.extend(planner.flush());
+
+        let mut failed = None;
+        for batch in batches {
+            let request = BatchUpdateBlobsRequest {
+                instance_name: self.instance_name.clone(),
+                requests: batch
+                    .into_iter()
+                    .map(|(digest, buff)| batch_update_request(self.codec, digest, buff))
+                    .collect(),
+                digest_function: self.digest_function_value(),
+            };
+            let resp = observe("BatchUpdateBlobs", async {
+                Ok(self.cas.clone().batch_update_blobs(request).await?)
+            })
+            .await?;
+            for r in resp.into_inner().responses {
+                if let (Some(d), Some(status)) = (r.digest, r.status.filter(|s| s.code != 0)) {
+                    warn!(hash = %d.hash, size = d.size_bytes, "failed to upload: {}", status.message);
+                    failed.get_or_insert(Error::Status {
+                        code: Code::from_i32(status.code),
+                        message: status.message,
+                        digest: Some(d),
+                    });
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// the digests of the blobs missing from CAS
+    #[instrument(level = "debug", skip_all, fields(blobs = digests.len()))]
+    pub async fn find_missing_blobs(&self, digests: Vec<Digest>) -> Result<Vec<Digest>> {
+        let request = FindMissingBlobsRequest {
+            instance_name: self.instance_name.clone(),
+            blob_digests: digests,
+            digest_function: self.digest_function_value(),
+        };
+        observe("FindMissingBlobs", async {
+            let resp = self.cas.clone().find_missing_blobs(request).await?;
+            Ok(resp.into_inner().missing_blob_digests)
+        })
+        .await
+    }
+
+    /// get the directories under the root with GetTree following the pages
+    /// till the end of the tree, or till `max_dirs` directories when it is not 0
+    #[instrument(
+        level = "debug",
+        skip(self, root),
+        fields(hash = %root.hash, size = root.size_bytes)
+    )]
+    pub async fn get_tree(&self, root: &Digest, max_dirs: usize) -> Result<Vec<Directory>> {
+        observe("GetTree", async {
+            let mut client = self.cas.clone();
+            let mut directories = vec![];
+            let mut page_token = String::from("");
+            loop {
+                let request = GetTreeRequest {
+                    instance_name: self.instance_name.clone(),
+                    root_digest: Some(root.clone()),
+                    page_size: 1000,
+                    page_token,
+                    digest_function: self.digest_function_value(),
+                };
+                let mut resp = client
+                    .get_tree(request)
+                    .await
+                    .map_err(|e| Error::from(e).with_digest(root))?;
+                let stream = resp.get_mut();
+
+                page_token = String::from("");
+                while let Some(mut message) = stream
+                    .message()
+                    .await
+                    .map_err(|e| Error::from(e).with_digest(root))?
+                {
+                    directories.append(&mut message.directories);
+                    page_token = message.next_page_token;
+                    if max_dirs > 0 && directories.len() >= max_dirs {
+                        directories.truncate(max_dirs);
+                        return Ok(directories);
+                    }
+                }
+
+                if page_token.is_empty() {
+                    return Ok(directories);
+                }
+            }
+        })
+        .await
+    }
+
+    async fn read(&self, resource_name: String, offset: i64, limit: i64) -> Result<Vec<u8>> {
+        let request = ReadRequest {
+            resource_name,
+            read_offset: offset,
+            read_limit: limit,
+        };
+
+        observe("Read", async {
+            let mut resp = self.bs.clone().read(request).await?;
+            let stream = resp.get_mut();
+
+            let mut content = vec![];
+            loop {
+                match stream.message().await? {
+                    Some(mut message) => {
+                        metrics::CAS_READ_BYTES.inc_by(message.data.len() as u64);
+                        content.append(&mut message.data)
+                    }
+                    None => break,
+                }
+            }
+            Ok(content)
+        })
+        .await
+    }
+
+    async fn write<T: AsyncRead + Send + Unpin + 'static>(
+        &self,
+        digest: &Digest,
+        stream: WriteRequestStream<T>,
+    ) -> Result<()> {
+        let read_error = stream.error.clone();
+        observe("Write", async {
+            let res = self.bs.clone().write(stream).await;
+            // the stream ended early on the error, so the write is aborted
+            if let Some(e) = read_error.lock().unwrap().take() {
+                return Err(Error::Io(e));
+            }
+            res.map(|_v| ())
+                .map_err(|e| Error::from(e).with_digest(digest))
+        })
+        .await
+    }
+
+    /// check the content read against the digest
+    fn verify(&self, digest: &Digest, data: &[u8]) -> Result<()> {
+        if data.len() as i64 != digest.size_bytes {
+            return Err(Error::corrupted(
+                digest,
+                format!("read {} bytes", data.len()),
+            ));
+        }
+        if self.codec.digest_function.hash(data) != digest.hash {
+            return Err(Error::corrupted(digest, "hash mismatch"));
+        }
+        Ok(())
+    }
+}
+
+/// record the latency of the RPC and its error by method, see [`metrics`]
+async fn observe<T, F>(method: &str, rpc: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let res = rpc.await;
+    metrics::CAS_RPC_DURATION
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+    if let Err(e) = &res {
+        let code = match e {
+            Error::Status { code, .. } => format!("{:?}", code),
+            Error::Corrupted { .. } => String::from("Corrupted"),
+            Error::Transport(_) => String::from("Transport"),
+            Error::Io(_) => String::from("Io"),
+            Error::Config(_) => String::from("Config"),
+        };
+        metrics::CAS_RPC_ERRORS
+            .with_label_values(&[method, &code])
+            .inc();
+    }
+    res
+}
+
+/// split the digests into batches whose responses fit into a gRPC message.
+/// Returns the batches and the blobs too large to be read in a batch
+pub(crate) fn group_digests(
+    digests: &[Digest],
+    max_bytes: i64,
+    max_count: usize,
+) -> (Vec<Vec<Digest>>, Vec<Digest>) {
+    let mut planner = BatchPlanner::new(max_bytes, max_count);
+    let mut batches = vec![];
+    let mut oversized = vec![];
+    let mut seen = HashSet::new();
+    for d in digests {
+        if !seen.insert(&d.hash) {
+            continue;
+        }
+        if !planner.fits(d.size_bytes) {
+            oversized.push(d.clone());
+            continue;
+        }
+        batches.extend(planner.push(d.clone(), d.size_bytes));
+    }
+    batches.extend(planner.flush());
+    (batches, oversized)
+}
+
+/// the blob is sent uncompressed when it fails to compress
+fn batch_update_request(
+    codec: Codec,
+    digest: Digest,
+    buff: Vec<u8>,
+) -> batch_update_blobs_request::Request {
+    if codec.batch_update {
+        if let Ok(data) = codec.compress(&buff) {
+            return batch_update_blobs_request::Request {
+                digest: Some(digest),
+                data,
+                compressor: compressor::Value::Zstd as i32,
+            };
+        }
+    }
+    batch_update_blobs_request::Request {
+        digest: Some(digest),
+        data: buff,
+        compressor: compressor::Value::Identity as i32,
+    }
+}
+
+/// WriteRequestStream turns the reader into the requests of a ByteStream
+/// write, the last request is an empty one with `finish_write` set
+struct WriteRequestStream<T: AsyncRead + Send + Unpin> {
+    read: T,
+    resource_name: String,
+    /// bytes sent so far
+    offset: i64,
+    finished: bool,
+    /// the error of the reader, the stream ends without `finish_write` on it
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl<T: AsyncRead + Send + Unpin> WriteRequestStream<T> {
+    fn new(read: T, resource_name: String) -> WriteRequestStream<T> {
+        WriteRequestStream {
+            read,
+            resource_name,
+            offset: 0,
+            finished: false,
+            error: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl<T: AsyncRead + Send + Unpin> Stream for WriteRequestStream<T> {
+    type Item = WriteRequest;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        let mut buff = [0; 16 * 1024];
+        let mut read_buff = ReadBuf::new(&mut buff);
+        match Pin::new(&mut self.read).poll_read(cx, &mut read_buff) {
+            Poll::Ready(Ok(())) => {
+                let data = read_buff.filled().to_vec();
+                let size = data.len() as i64;
+                // the offset of a request is where its data starts
+                let write_offset = self.offset;
+                self.offset += size;
+                self.finished = size == 0;
+                Poll::Ready(Some(WriteRequest {
+                    resource_name: self.resource_name.clone(),
+                    write_offset,
+                    finish_write: size == 0,
+                    data,
+                }))
+            }
+            Poll::Ready(Err(e)) => {
+                self.finished = true;
+                *self.error.lock().unwrap() = Some(e);
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::batch::BATCH_ENTRY_OVERHEAD;
+    use super::super::fake::FakeCas;
+    use super::*;
+
+    fn digest(hash: &str, size: i64) -> Digest {
+        Digest {
+            hash: hash.to_string(),
+            size_bytes: size,
+        }
+    }
+
+    #[test]
+    fn test_group_digests() {
+        let max = 2 * (100 + BATCH_ENTRY_OVERHEAD);
+        let digests = vec![
+            digest("a", 100),
+            digest("b", 100),
+            digest("a", 100),
+            digest("c", 100),
+            digest("big", max),
+        ];
+        let (batches, oversized) = group_digests(&digests, max, 10);
+        let batches: Vec<Vec<&str>> = batches
+            .iter()
+            .map(|b| b.iter().map(|d| d.hash.as_str()).collect())
+            .collect();
+        assert_eq!(batches, vec![vec!["a", "b"], vec!["c"]]);
+        assert_eq!(oversized, vec![digest("big", max)]);
+    }
+
+    #[test]
+    fn test_group_digests_by_count() {
+        let digests = vec![digest("a", 1), digest("b", 1), digest("c", 1)];
+        let (batches, oversized) = group_digests(&digests, 1024 * 1024, 2);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 2);
+        assert!(oversized.is_empty());
+    }
+
+    #[test]
+    fn test_async_client() {
+        let cas = FakeCas::new();
+        let server = cas.start().unwrap();
+        let dir = cas.insert_dir(&Directory::default());
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let client = Client::builder()
+                .endpoint(server.endpoint.clone())
+                .instance_name("main")
+                .connect()
+                .await
+                .unwrap();
+            assert_eq!(client.digest_function(), DigestFunction::Sha256);
+
+            let data = b"hello world".to_vec();
+            let d = digest(&DigestFunction::Sha256.hash(&data), data.len() as i64);
+            let missing = client.find_missing_blobs(vec![d.clone()]).await.unwrap();
+            assert_eq!(missing, vec![d.clone()]);
+
+            client
+                .batch_update_blobs(vec![(d.clone(), data.clone())])
+                .await
+                .unwrap();
+            let missing = client.find_missing_blobs(vec![d.clone()]).await.unwrap();
+            assert!(missing.is_empty());
+            assert_eq!(client.read_blob(&d).await.unwrap(), data);
+            assert_eq!(client.read_range(&d, 6, 5).await.unwrap(), b"world");
+
+            let blobs = client.batch_read_blobs(&[d.clone()]).await.unwrap();
+            assert_eq!(blobs[&d.hash], data);
+            assert_eq!(client.get_tree(&dir, 0).await.unwrap().len(), 1);
+        });
+        assert_eq!(cas.calls("BatchUpdateBlobs"), 1);
+    }
+
+    #[test]
+    fn test_blake3_requests() {
+        let cas = FakeCas::new();
+        cas.set_capabilities(ServerCapabilities {
+            cache_capabilities: Some(CacheCapabilities {
+                digest_functions: vec![
+                    digest_function::Value::Sha256 as i32,
+                    digest_function::Value::Blake3 as i32,
+                ],
+                max_batch_total_size_bytes: 1024 * 1024,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let server = cas.start().unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let client = Client::builder()
+                .endpoint(server.endpoint.clone())
+                .options(ClientOptions {
+                    digest_function: Some(DigestFunction::Blake3),
+                    ..Default::default()
+                })
+                .connect()
+                .await
+                .unwrap();
+            assert_eq!(client.digest_function(), DigestFunction::Blake3);
+
+            // the fake checks the hashes of the batch updates by the digest
+            // function of the request
+            let data = b"hello world".to_vec();
+            let d = digest(&DigestFunction::Blake3.hash(&data), data.len() as i64);
+            client
+                .batch_update_blobs(vec![(d.clone(), data.clone())])
+                .await
+                .unwrap();
+            assert!(client
+                .find_missing_blobs(vec![d.clone()])
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(client.read_blob(&d).await.unwrap(), data);
+        });
+    }
+
+    #[test]
+    fn test_write_stream() {
+        let cas = FakeCas::new();
+        let server = cas.start().unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let client = Client::builder()
+                .endpoint(server.endpoint.clone())
+                .connect()
+                .await
+                .unwrap();
+            // several requests, and a single empty one
+            for data in [vec![7u8; 40 * 1024], vec![]] {
+                let d = digest(&DigestFunction::Sha256.hash(&data), data.len() as i64);
+                client.write_blob(&d, data.clone()).await.unwrap();
+                assert_eq!(cas.get(&d.hash), Some(data));
+            }
+        });
+        assert_eq!(cas.calls("Write"), 2);
+    }
+
+    /// a reader failing after the first chunk
+    struct FailingReader {
+        sent: bool,
+    }
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.sent {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, "disk failed")));
+            }
+            self.sent = true;
+            buf.put_slice(&[1; 1024]);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_write_read_error() {
+        let cas = FakeCas::new();
+        let server = cas.start().unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let client = Client::builder()
+                .endpoint(server.endpoint.clone())
+                .connect()
+                .await
+                .unwrap();
+            let data = vec![1; 2048];
+            let d = digest(&DigestFunction::Sha256.hash(&data), data.len() as i64);
+            let name = client.codec.write_resource_name("", &d.hash, d.size_bytes);
+            let stream = WriteRequestStream::new(FailingReader { sent: false }, name);
+            match client.write(&d, stream).await {
+                Err(Error::Io(e)) => assert_eq!(e.to_string(), "disk failed"),
+                res => panic!("unexpected {:?}", res.map_err(|e| e.to_string())),
+            }
+            assert_eq!(cas.get(&d.hash), None);
+        });
+    }
+}
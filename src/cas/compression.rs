@@ -0,0 +1,210 @@
+//! Compressed blob transfer.
+//!
+//! Blobs are sent compressed through the `compressed-blobs/zstd/` resource
+//! names of ByteStream and the `compressor` fields of the batch APIs when
+//! the server supports zstd. The digests are always of the uncompressed blobs.
+use super::capabilities::Capabilities;
+use crate::hash::DigestFunction;
+use anyhow::Result;
+use std::io;
+use std::str::FromStr;
+use tracing::debug;
+use uuid::Uuid;
+
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Compression of the blobs being transferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd when the server supports it
+    Auto,
+    Zstd,
+    None,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Compression::Auto),
+            "zstd" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown compression {}, expect one of auto, zstd and none",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionOptions {
+    pub compression: Compression,
+
+    /// zstd compression level, higher is smaller but slower
+    pub level: i32,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            compression: Compression::Auto,
+            level: DEFAULT_LEVEL,
+        }
+    }
+}
+
+/// Codec is how the blobs are encoded on the wire, as agreed with the
+/// server for a connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Codec {
+    /// the hash function of the digests
+    pub digest_function: DigestFunction,
+
+    /// compress the ByteStream reads and writes
+    pub bytestream: bool,
+
+    /// compress the batch updates
+    pub batch_update: bool,
+
+    /// accept compressed blobs in the batch reads, the server compresses
+    /// them when it supports the compressed ByteStream reads
+    pub batch_read: bool,
+
+    pub level: i32,
+}
+
+impl Codec {
+    pub fn negotiate(options: &CompressionOptions, caps: &Capabilities) -> Result<Codec> {
+        let bytestream = caps.supports_compressor("ZSTD");
+        let batch_update = caps.supports_batch_update_compressor("ZSTD");
+        match options.compression {
+            Compression::None => return Ok(Codec::default()),
+            Compression::Zstd if !bytestream && !batch_update => {
+                return Err(anyhow::Error::msg(
+                    "server does not support zstd compression",
+                ))
+            }
+            Compression::Auto if !bytestream && !batch_update => {
+                debug!("server does not support zstd compression, blobs are sent uncompressed")
+            }
+            _ => {}
+        }
+        Ok(Codec {
+            digest_function: DigestFunction::default(),
+            bytestream,
+            batch_update,
+            batch_read: bytestream,
+            level: options.level,
+        })
+    }
+
+    /// ByteStream resource name to read the whole blob
+    pub(crate) fn read_resource_name(&self, instance_name: &str, hash: &str, size: i64) -> String {
+        format!("{}/{}", instance_name, self.blob_path(hash, size))
+    }
+
+    /// ByteStream resource name to upload the blob
+    pub(crate) fn write_resource_name(&self, instance_name: &str, hash: &str, size: i64) -> String {
+        let uuid = Uuid::new_v4();
+        format!(
+            "{}/uploads/{}/{}",
+            instance_name,
+            uuid,
+            self.blob_path(hash, size)
+        )
+    }
+
+    /// `blobs/[{digest function}/]{hash}/{size}` or the compressed form
+    /// `compressed-blobs/zstd/[{digest function}/]{hash}/{size}`
+    fn blob_path(&self, hash: &str, size: i64) -> String {
+        let mut path = String::from(if self.bytestream {
+            "compressed-blobs/zstd/"
+        } else {
+            "blobs/"
+        });
+        if let Some(segment) = self.digest_function.resource_segment() {
+            path.push_str(segment);
+            path.push('/');
+        }
+        path.push_str(&format!("{}/{}", hash, size));
+        path
+    }
+
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::bulk::compress(data, self.level)
+    }
+}
+
+/// decompress the zstd blob of the uncompressed `size`
+pub(crate) fn decompress(data: &[u8], size: i64) -> Result<Vec<u8>> {
+    let blob = zstd::bulk::decompress(data, size as usize)?;
+    if blob.len() as i64 != size {
+        return Err(anyhow::Error::msg(format!(
+            "decompressed blob size {} does not match the digest size {}",
+            blob.len(),
+            size
+        )));
+    }
+    Ok(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let codec = Codec {
+            digest_function: DigestFunction::Sha256,
+            bytestream: true,
+            batch_update: true,
+            batch_read: true,
+            level: DEFAULT_LEVEL,
+        };
+        let data = "hello world ".repeat(100).into_bytes();
+        let compressed = codec.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed, data.len() as i64).unwrap(), data);
+        assert!(decompress(&compressed, data.len() as i64 + 1).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut caps = Capabilities::default();
+        let auto = CompressionOptions::default();
+        let zstd = CompressionOptions {
+            compression: Compression::Zstd,
+            level: 10,
+        };
+
+        let codec = Codec::negotiate(&auto, &caps).unwrap();
+        assert!(!codec.bytestream && !codec.batch_update && !codec.batch_read);
+        assert!(Codec::negotiate(&zstd, &caps).is_err());
+
+        caps.compressors = vec![String::from("ZSTD")];
+        let codec = Codec::negotiate(&zstd, &caps).unwrap();
+        assert!(codec.bytestream && !codec.batch_update && codec.batch_read);
+        assert_eq!(codec.level, 10);
+        assert_eq!(
+            codec.read_resource_name("main", "abc", 3),
+            "main/compressed-blobs/zstd/abc/3"
+        );
+    }
+
+    #[test]
+    fn test_blob_path() {
+        let mut codec = Codec::default();
+        assert_eq!(codec.blob_path("abc", 3), "blobs/abc/3");
+
+        codec.digest_function = DigestFunction::Blake3;
+        assert_eq!(codec.blob_path("abc", 3), "blobs/blake3/abc/3");
+
+        codec.bytestream = true;
+        assert_eq!(
+            codec.blob_path("abc", 3),
+            "compressed-blobs/zstd/blake3/abc/3"
+        );
+    }
+}
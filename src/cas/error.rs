@@ -0,0 +1,177 @@
+//! Errors of the CAS clients.
+//!
+//! The errors keep the gRPC status code of the server and the digest of the
+//! blob the request was for, so that the callers can tell a missing blob
+//! from a denied or a throttled request.
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
+use std::fmt;
+use std::io;
+use tonic::Code;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// the server answered with a status other than OK
+    Status {
+        code: Code,
+        message: String,
+        /// None for the requests of many blobs
+        digest: Option<Digest>,
+    },
+
+    /// the content read does not match the digest, eg. a truncated or a
+    /// corrupted blob
+    Corrupted { digest: Digest, message: String },
+
+    /// failed to connect to the server
+    Transport(tonic::transport::Error),
+
+    /// failed locally, eg. to read the file being uploaded
+    Io(io::Error),
+
+    /// the client is not set up right or does not agree with the server,
+    /// eg. a missing `CAS_ENDPOINT` or an unsupported digest function
+    Config(String),
+}
+
+impl Error {
+    pub(crate) fn config(message: impl Into<String>) -> Error {
+        Error::Config(message.into())
+    }
+
+    pub(crate) fn corrupted(digest: &Digest, message: impl Into<String>) -> Error {
+        Error::Corrupted {
+            digest: digest.clone(),
+            message: message.into(),
+        }
+    }
+
+    /// attach the digest of the blob the failed request was for
+    pub(crate) fn with_digest(self, digest: &Digest) -> Error {
+        match self {
+            Error::Status {
+                code,
+                message,
+                digest: None,
+            } => Error::Status {
+                code,
+                message,
+                digest: Some(digest.clone()),
+            },
+            e => e,
+        }
+    }
+
+    /// the status code from the server, None for the errors not from the server
+    pub fn code(&self) -> Option<Code> {
+        match self {
+            Error::Status { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// the digest of the blob the error is about
+    pub fn digest(&self) -> Option<&Digest> {
+        match self {
+            Error::Status { digest, .. } => digest.as_ref(),
+            Error::Corrupted { digest, .. } => Some(digest),
+            _ => None,
+        }
+    }
+
+    /// whether the same request could succeed later, eg. when the server is
+    /// throttling or the connection dropped
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Status { code, .. } => matches!(
+                code,
+                Code::Unavailable
+                    | Code::ResourceExhausted
+                    | Code::Aborted
+                    | Code::DeadlineExceeded
+            ),
+            Error::Transport(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.code() == Some(Code::NotFound)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Status {
+                code,
+                message,
+                digest: Some(d),
+            } => write!(f, "{:?} {}/{}: {}", code, d.hash, d.size_bytes, message),
+            Error::Status { code, message, .. } => write!(f, "{:?}: {}", code, message),
+            Error::Corrupted { digest, message } => write!(
+                f,
+                "corrupted blob {}/{}: {}",
+                digest.hash, digest.size_bytes, message
+            ),
+            Error::Transport(e) => write!(f, "failed to connect to CAS: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        Error::Status {
+            code: status.code(),
+            message: status.message().to_string(),
+            digest: None,
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(e: tonic::transport::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let digest = Digest {
+            hash: String::from("abc"),
+            size_bytes: 3,
+        };
+        let e = Error::from(tonic::Status::not_found("missing")).with_digest(&digest);
+        assert!(e.is_not_found());
+        assert!(!e.is_retryable());
+        assert_eq!(e.digest(), Some(&digest));
+        assert_eq!(e.to_string(), "NotFound abc/3: missing");
+
+        let e = Error::from(tonic::Status::resource_exhausted("slow down"));
+        assert!(e.is_retryable());
+        assert_eq!(e.digest(), None);
+    }
+}
@@ -0,0 +1,576 @@
+//! In-memory CAS server for the tests.
+//!
+//! FakeCas implements the ContentAddressableStorage, ByteStream and
+//! Capabilities services on a local port, so that the clients can be
+//! tested end to end without a live `CAS_ENDPOINT`. Faults can be injected
+//! per blob to cover the error handling of the clients.
+use crate::hash::DigestFunction;
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::capabilities_server::{
+    Capabilities as CapabilitiesService, CapabilitiesServer,
+};
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::content_addressable_storage_server::{
+    ContentAddressableStorage, ContentAddressableStorageServer,
+};
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::*;
+use bazel_remote_apis_rs::google::bytestream::byte_stream_server::{ByteStream, ByteStreamServer};
+use bazel_remote_apis_rs::google::bytestream::{
+    QueryWriteStatusRequest, QueryWriteStatusResponse, ReadRequest, ReadResponse, WriteRequest,
+    WriteResponse,
+};
+use bazel_remote_apis_rs::google::rpc::Status as RpcStatus;
+use futures::Stream;
+use lazy_static::lazy_static;
+use prost::Message;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::error;
+
+/// size of the chunks of the ByteStream reads
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    /// the clients are configured by the environment, so only one fake
+    /// server is served at a time
+    static ref ENV_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Faults injected into the responses of the fake server
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// ByteStream reads and writes of these hashes fail halfway
+    pub drop_stream: HashSet<String>,
+
+    /// reads of these hashes return corrupted bytes of the same size
+    pub wrong_bytes: HashSet<String>,
+
+    /// batch reads and updates of these hashes fail with the status code
+    pub batch_errors: HashMap<String, Code>,
+
+    /// FindMissingBlobs fails with the status code
+    pub find_missing_error: Option<Code>,
+
+    /// every call is delayed by this long
+    pub delay: Option<Duration>,
+}
+
+/// FakeCas is the state of the fake server. Clones share the same state
+#[derive(Clone, Default)]
+pub struct FakeCas {
+    state: Arc<Mutex<FakeState>>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    blobs: HashMap<String, Vec<u8>>,
+    faults: Faults,
+    capabilities: ServerCapabilities,
+    /// number of calls by method name, eg. `BatchUpdateBlobs`
+    calls: HashMap<&'static str, usize>,
+}
+
+impl FakeCas {
+    /// a server supporting SHA256 without compression
+    pub fn new() -> FakeCas {
+        let cas = FakeCas::default();
+        cas.set_capabilities(ServerCapabilities {
+            cache_capabilities: Some(CacheCapabilities {
+                digest_functions: vec![digest_function::Value::Sha256 as i32],
+                max_batch_total_size_bytes: 1024 * 1024,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        cas
+    }
+
+    pub fn set_capabilities(&self, capabilities: ServerCapabilities) {
+        self.state().capabilities = capabilities;
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.state().faults = faults;
+    }
+
+    /// store the blob and return its SHA256 digest
+    pub fn insert(&self, data: Vec<u8>) -> Digest {
+        self.insert_with(DigestFunction::Sha256, data)
+    }
+
+    pub fn insert_with(&self, digest_function: DigestFunction, data: Vec<u8>) -> Digest {
+        let digest = Digest {
+            hash: digest_function.hash(&data),
+            size_bytes: data.len() as i64,
+        };
+        self.state().blobs.insert(digest.hash.clone(), data);
+        digest
+    }
+
+    /// store the encoded directory and return its digest
+    pub fn insert_dir(&self, dir: &Directory) -> Digest {
+        self.insert(dir.encode_to_vec())
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Vec<u8>> {
+        self.state().blobs.get(hash).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state().blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of calls of the method, eg. `BatchUpdateBlobs`
+    pub fn calls(&self, method: &str) -> usize {
+        self.state().calls.get(method).copied().unwrap_or(0)
+    }
+
+    /// serve on a local port and point `CAS_ENDPOINT` to it until the
+    /// returned server is dropped
+    pub fn start(&self) -> Result<FakeServer> {
+        // the lock is poisoned when another test failed while holding it
+        let guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        let listener = rt.block_on(TcpListener::bind("127.0.0.1:0"))?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let incoming = Box::pin(futures::stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(stream, _)| stream);
+            Some((conn, listener))
+        }));
+        let router = Server::builder()
+            .add_service(ContentAddressableStorageServer::new(self.clone()))
+            .add_service(ByteStreamServer::new(self.clone()))
+            .add_service(CapabilitiesServer::new(self.clone()));
+        rt.spawn(async move {
+            let res = router
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = signal.await;
+                })
+                .await;
+            if let Err(e) = res {
+                error!("fake cas server failed {}", e);
+            }
+        });
+
+        env::set_var("CAS_ENDPOINT", &endpoint);
+        env::remove_var("INSTANCE_NAME");
+        Ok(FakeServer {
+            endpoint,
+            shutdown: Some(shutdown),
+            rt: Some(rt),
+            _guard: guard,
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    /// count the call and wait for the injected delay
+    async fn call(&self, method: &'static str) -> Faults {
+        let faults = {
+            let mut state = self.state();
+            *state.calls.entry(method).or_insert(0) += 1;
+            state.faults.clone()
+        };
+        if let Some(delay) = faults.delay {
+            tokio::time::sleep(delay).await;
+        }
+        faults
+    }
+
+    fn read_blob(&self, faults: &Faults, hash: &str) -> Option<Vec<u8>> {
+        let mut data = self.get(hash)?;
+        if faults.wrong_bytes.contains(hash) {
+            for b in data.iter_mut() {
+                *b = !*b;
+            }
+        }
+        Some(data)
+    }
+
+    /// store the blob when its digest matches, by any of the digest functions
+    /// when the request does not say which one
+    fn write_blob(
+        &self,
+        digest: &Digest,
+        data: Vec<u8>,
+        digest_function: Option<DigestFunction>,
+    ) -> Result<(), Status> {
+        let functions = match digest_function {
+            Some(f) => vec![f],
+            None => vec![DigestFunction::Sha256, DigestFunction::Blake3],
+        };
+        let matched = data.len() as i64 == digest.size_bytes
+            && functions.iter().any(|f| f.hash(&data) == digest.hash);
+        if !matched {
+            return Err(Status::invalid_argument(format!(
+                "data does not match the digest {}/{}",
+                digest.hash, digest.size_bytes
+            )));
+        }
+        self.state().blobs.insert(digest.hash.clone(), data);
+        Ok(())
+    }
+
+    fn supports_zstd(&self) -> bool {
+        let state = self.state();
+        state
+            .capabilities
+            .cache_capabilities
+            .as_ref()
+            .map_or(false, |c| {
+                c.supported_compressors
+                    .contains(&(compressor::Value::Zstd as i32))
+            })
+    }
+}
+
+/// FakeServer is a running fake server, it stops on drop
+pub struct FakeServer {
+    /// the endpoint in the form of `http://127.0.0.1:{port}`
+    pub endpoint: String,
+    shutdown: Option<oneshot::Sender<()>>,
+    rt: Option<Runtime>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(rt) = self.rt.take() {
+            rt.shutdown_background();
+        }
+    }
+}
+
+/// ResourceName is a parsed ByteStream resource name of a blob
+#[derive(Debug, PartialEq)]
+struct ResourceName {
+    digest: Digest,
+    compressed: bool,
+}
+
+/// parse `[{instance}/][uploads/{uuid}/]blobs/[{digest function}/]{hash}/{size}`
+/// or the `compressed-blobs/zstd/` form
+fn parse_resource_name(name: &str) -> Result<ResourceName, Status> {
+    let invalid = || Status::invalid_argument(format!("invalid resource name {}", name));
+    let segments: Vec<_> = name.split('/').collect();
+    let start = segments
+        .iter()
+        .position(|s| *s == "blobs" || *s == "compressed-blobs")
+        .ok_or_else(invalid)?;
+    let compressed = segments[start] == "compressed-blobs";
+    if segments.len() < start + 3 || (compressed && segments[start + 1] != "zstd") {
+        return Err(invalid());
+    }
+    let size = segments[segments.len() - 1]
+        .parse::<i64>()
+        .map_err(|_| invalid())?;
+    Ok(ResourceName {
+        digest: Digest {
+            hash: segments[segments.len() - 2].to_string(),
+            size_bytes: size,
+        },
+        compressed,
+    })
+}
+
+/// the digest function of a request, None when it is not set
+fn request_digest_function(value: i32) -> Option<DigestFunction> {
+    match digest_function::Value::from_i32(value) {
+        Some(digest_function::Value::Sha256) => Some(DigestFunction::Sha256),
+        Some(digest_function::Value::Blake3) => Some(DigestFunction::Blake3),
+        _ => None,
+    }
+}
+
+fn rpc_status(code: Code) -> Option<RpcStatus> {
+    Some(RpcStatus {
+        code: code as i32,
+        ..Default::default()
+    })
+}
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl ContentAddressableStorage for FakeCas {
+    async fn find_missing_blobs(
+        &self,
+        request: Request<FindMissingBlobsRequest>,
+    ) -> Result<Response<FindMissingBlobsResponse>, Status> {
+        let faults = self.call("FindMissingBlobs").await;
+        if let Some(code) = faults.find_missing_error {
+            return Err(Status::new(code, "injected fault"));
+        }
+        let state = self.state();
+        let missing_blob_digests = request
+            .into_inner()
+            .blob_digests
+            .into_iter()
+            .filter(|d| !state.blobs.contains_key(&d.hash))
+            .collect();
+        Ok(Response::new(FindMissingBlobsResponse {
+            missing_blob_digests,
+        }))
+    }
+
+    async fn batch_update_blobs(
+        &self,
+        request: Request<BatchUpdateBlobsRequest>,
+    ) -> Result<Response<BatchUpdateBlobsResponse>, Status> {
+        let faults = self.call("BatchUpdateBlobs").await;
+        let request = request.into_inner();
+        let function = request_digest_function(request.digest_function);
+        let mut responses = vec![];
+        for r in request.requests {
+            let digest = r.digest.unwrap_or_default();
+            let status = if let Some(code) = faults.batch_errors.get(&digest.hash) {
+                rpc_status(*code)
+            } else {
+                let data = if r.compressor == compressor::Value::Zstd as i32 {
+                    zstd::stream::decode_all(&r.data[..]).ok()
+                } else {
+                    Some(r.data)
+                };
+                match data.map(|data| self.write_blob(&digest, data, function)) {
+                    Some(Ok(())) => None,
+                    _ => rpc_status(Code::InvalidArgument),
+                }
+            };
+            responses.push(batch_update_blobs_response::Response {
+                digest: Some(digest),
+                status,
+            });
+        }
+        Ok(Response::new(BatchUpdateBlobsResponse { responses }))
+    }
+
+    async fn batch_read_blobs(
+        &self,
+        request: Request<BatchReadBlobsRequest>,
+    ) -> Result<Response<BatchReadBlobsResponse>, Status> {
+        let faults = self.call("BatchReadBlobs").await;
+        let request = request.into_inner();
+        let zstd = self.supports_zstd()
+            && request
+                .acceptable_compressors
+                .contains(&(compressor::Value::Zstd as i32));
+
+        let mut responses = vec![];
+        for digest in request.digests {
+            let mut response = batch_read_blobs_response::Response {
+                digest: Some(digest.clone()),
+                ..Default::default()
+            };
+            match (
+                faults.batch_errors.get(&digest.hash),
+                self.read_blob(&faults, &digest.hash),
+            ) {
+                (Some(code), _) => response.status = rpc_status(*code),
+                (None, None) => response.status = rpc_status(Code::NotFound),
+                (None, Some(data)) if zstd => {
+                    response.data = zstd::bulk::compress(&data, 0).unwrap();
+                    response.compressor = compressor::Value::Zstd as i32;
+                }
+                (None, Some(data)) => response.data = data,
+            }
+            responses.push(response);
+        }
+        Ok(Response::new(BatchReadBlobsResponse { responses }))
+    }
+
+    type GetTreeStream = ResponseStream<GetTreeResponse>;
+
+    async fn get_tree(
+        &self,
+        request: Request<GetTreeRequest>,
+    ) -> Result<Response<Self::GetTreeStream>, Status> {
+        self.call("GetTree").await;
+        let request = request.into_inner();
+        let root = request.root_digest.unwrap_or_default();
+
+        // breadth first from the root, the page token is the offset
+        let mut directories = vec![];
+        let mut queue = VecDeque::from(vec![root]);
+        while let Some(digest) = queue.pop_front() {
+            let data = self
+                .get(&digest.hash)
+                .ok_or_else(|| Status::not_found(format!("directory {} not found", digest.hash)))?;
+            let dir = Directory::decode(&data[..])
+                .map_err(|e| Status::invalid_argument(format!("invalid directory {}", e)))?;
+            queue.extend(dir.directories.iter().filter_map(|d| d.digest.clone()));
+            directories.push(dir);
+        }
+
+        let offset = request.page_token.parse::<usize>().unwrap_or(0);
+        let page_size = match request.page_size {
+            size if size > 0 => size as usize,
+            _ => directories.len(),
+        };
+        let end = std::cmp::min(offset + page_size, directories.len());
+        let next_page_token = if end < directories.len() {
+            end.to_string()
+        } else {
+            String::new()
+        };
+        let page = GetTreeResponse {
+            directories: directories.drain(offset.min(end)..end).collect(),
+            next_page_token,
+        };
+        let stream = futures::stream::iter(vec![Ok(page)]);
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[tonic::async_trait]
+impl ByteStream for FakeCas {
+    type ReadStream = ResponseStream<ReadResponse>;
+
+    async fn read(
+        &self,
+        request: Request<ReadRequest>,
+    ) -> Result<Response<Self::ReadStream>, Status> {
+        let faults = self.call("Read").await;
+        let request = request.into_inner();
+        let name = parse_resource_name(&request.resource_name)?;
+        let data = self
+            .read_blob(&faults, &name.digest.hash)
+            .ok_or_else(|| Status::not_found(format!("blob {} not found", name.digest.hash)))?;
+
+        let offset = request.read_offset.max(0) as usize;
+        if offset > data.len() {
+            return Err(Status::out_of_range("read offset beyond the blob"));
+        }
+        let end = match request.read_limit {
+            limit if limit > 0 => std::cmp::min(offset + limit as usize, data.len()),
+            _ => data.len(),
+        };
+        let mut data = data[offset..end].to_vec();
+        if name.compressed {
+            data = zstd::bulk::compress(&data, 0).map_err(|e| Status::internal(e.to_string()))?;
+        }
+
+        let mut messages: Vec<_> = data
+            .chunks(READ_CHUNK_SIZE)
+            .map(|c| Ok(ReadResponse { data: c.to_vec() }))
+            .collect();
+        if faults.drop_stream.contains(&name.digest.hash) {
+            messages.truncate(messages.len() / 2);
+            messages.push(Err(Status::unavailable("stream dropped")));
+        }
+        Ok(Response::new(Box::pin(futures::stream::iter(messages))))
+    }
+
+    async fn write(
+        &self,
+        request: Request<Streaming<WriteRequest>>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let faults = self.call("Write").await;
+        let mut stream = request.into_inner();
+
+        let mut name = None;
+        let mut data = vec![];
+        let mut finished = false;
+        while let Some(mut message) = stream.message().await? {
+            if name.is_none() {
+                let parsed = parse_resource_name(&message.resource_name)?;
+                if faults.drop_stream.contains(&parsed.digest.hash) {
+                    return Err(Status::unavailable("stream dropped"));
+                }
+                name = Some(parsed);
+            }
+            if message.write_offset != data.len() as i64 {
+                return Err(Status::invalid_argument("unexpected write offset"));
+            }
+            data.append(&mut message.data);
+            if message.finish_write {
+                finished = true;
+                break;
+            }
+        }
+
+        let name = name.ok_or_else(|| Status::invalid_argument("empty write"))?;
+        // a client closing the stream early must not commit a partial blob
+        if !finished {
+            return Err(Status::invalid_argument(
+                "write closed without finish_write",
+            ));
+        }
+        if name.compressed {
+            data = zstd::stream::decode_all(&data[..])
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+        let committed_size = data.len() as i64;
+        self.write_blob(&name.digest, data, None)?;
+        Ok(Response::new(WriteResponse { committed_size }))
+    }
+
+    async fn query_write_status(
+        &self,
+        _request: Request<QueryWriteStatusRequest>,
+    ) -> Result<Response<QueryWriteStatusResponse>, Status> {
+        Err(Status::unimplemented("resumable writes are not supported"))
+    }
+}
+
+#[tonic::async_trait]
+impl CapabilitiesService for FakeCas {
+    async fn get_capabilities(
+        &self,
+        _request: Request<GetCapabilitiesRequest>,
+    ) -> Result<Response<ServerCapabilities>, Status> {
+        self.call("GetCapabilities").await;
+        Ok(Response::new(self.state().capabilities.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resource_name() {
+        let digest = |hash: &str, size| Digest {
+            hash: hash.to_string(),
+            size_bytes: size,
+        };
+        assert_eq!(
+            parse_resource_name("blobs/abc/3").unwrap(),
+            ResourceName {
+                digest: digest("abc", 3),
+                compressed: false
+            }
+        );
+        assert_eq!(
+            parse_resource_name("main/uploads/uuid/compressed-blobs/zstd/blake3/abc/3").unwrap(),
+            ResourceName {
+                digest: digest("abc", 3),
+                compressed: true
+            }
+        );
+        assert!(parse_resource_name("main/abc/3").is_err());
+        assert!(parse_resource_name("compressed-blobs/gzip/abc/3").is_err());
+        assert!(parse_resource_name("blobs/abc/x").is_err());
+    }
+}
@@ -1,3 +1,51 @@
 mod auth;
+mod batch;
 
 pub mod blocking;
+pub mod capabilities;
+mod client;
+pub mod compression;
+pub mod error;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fake;
+
+pub use client::{Client, ClientBuilder, ClientOptions, UploadOptions};
+pub use error::Error;
+
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
+
+/// parse a digest in the form of `{hash}/{size}`
+pub fn parse_digest(digest: &str) -> Result<Digest> {
+    let tokens: Vec<_> = digest.split("/").collect();
+    if tokens.len() != 2 {
+        return Err(anyhow::Error::msg("malformed digest"));
+    }
+
+    let size = tokens[1]
+        .parse::<i64>()
+        .map_err(|e| anyhow::Error::msg(format!("malformed digest size {}", e)))?;
+    Ok(Digest {
+        hash: tokens[0].to_string(),
+        size_bytes: size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_digest() {
+        let digest = parse_digest("3acb2d70/165").unwrap();
+        assert_eq!(digest.hash, "3acb2d70");
+        assert_eq!(digest.size_bytes, 165);
+    }
+
+    #[test]
+    fn test_parse_malformed_digest() {
+        assert!(parse_digest("3acb2d70").is_err());
+        assert!(parse_digest("3acb2d70/abc").is_err());
+        assert!(parse_digest("3acb2d70/165/1").is_err());
+    }
+}
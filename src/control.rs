@@ -0,0 +1,133 @@
+//! Control protocol between a running `cfsd` and `fsx daemon`.
+//!
+//! The daemon listens on a Unix socket. Each request and each response is a
+//! single line of JSON.
+use crate::cas::blocking::CacheStats;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// The default path of the control socket
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/cfsd.sock";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// Query the state of the daemon
+    Status,
+
+    /// Drop all the cached blobs
+    Flush,
+
+    /// Fetch a blob into the cache ahead of reads. When `recursive` is set,
+    /// the digest is a directory and the whole tree under it is fetched
+    Prefetch { digest: String, recursive: bool },
+
+    /// Swap the root directory of the live mount to a new digest. Unchanged
+    /// subtrees keep their inodes
+    Swap { digest: String },
+
+    /// Upload the merged view of the writable mount as a new tree
+    Commit,
+
+    /// Unmount the filesystem which stops the daemon
+    Unmount,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Status(Status),
+
+    Flushed { blobs: u64, bytes: u64 },
+
+    Prefetched { blobs: u64, bytes: u64 },
+
+    Swapped { digest: String, invalidated: u64 },
+
+    Committed { digest: String },
+
+    Unmounted,
+
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    /// the mounted roots
+    pub mounts: Vec<MountStatus>,
+
+    /// blob cache statistics
+    pub cache: CacheStats,
+
+    /// number of inodes being allocated
+    pub inodes: u64,
+
+    /// number of directories with cached entries
+    pub directories: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountStatus {
+    pub mountpoint: String,
+
+    /// root directory digest in the form of `{hash}/{size}`
+    pub digest: String,
+}
+
+/// send a single request to the daemon listening at `socket` and wait for the response
+pub fn call<P: AsRef<Path>>(socket: P, request: &Request) -> Result<Response> {
+    let socket = socket.as_ref();
+    let stream = UnixStream::connect(socket).map_err(|e| {
+        anyhow::Error::msg(format!(
+            "failed to connect to control socket {}: {}",
+            socket.display(),
+            e
+        ))
+    })?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(anyhow::Error::msg("control socket closed without response"));
+    }
+    serde_json::from_str(&line).map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_encoding() {
+        let request = Request::Prefetch {
+            digest: String::from("3acb2d70/165"),
+            recursive: true,
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"cmd":"prefetch","digest":"3acb2d70/165","recursive":true}"#
+        );
+
+        let request: Request = serde_json::from_str(r#"{"cmd":"status"}"#).unwrap();
+        assert!(matches!(request, Request::Status));
+    }
+
+    #[test]
+    fn test_response_encoding() {
+        let response = Response::Flushed {
+            blobs: 2,
+            bytes: 10,
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"result":"flushed","blobs":2,"bytes":10}"#
+        );
+    }
+}
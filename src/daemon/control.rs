@@ -0,0 +1,94 @@
+use super::fuse::MountHandle;
+use anyhow::Result;
+use cfs::cas;
+use cfs::control::{Request, Response};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Start the control server listening at the unix socket `path`
+///
+/// Each connection is served on its own thread so that a long prefetch
+/// does not block the status queries.
+pub fn serve(path: &str, handle: MountHandle) -> Result<()> {
+    // remove the stale socket left by a previous daemon
+    if Path::new(path).exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| {
+        anyhow::Error::msg(format!("failed to bind control socket {}: {}", path, e))
+    })?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handle = handle.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = serve_connection(stream, handle) {
+                            warn!("control connection failed {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept control connection {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream, handle: MountHandle) -> Result<()> {
+    let reader = BufReader::new(&stream);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => dispatch(&handle, request),
+            Err(e) => Response::Error {
+                message: format!("malformed request {}", e),
+            },
+        };
+
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        (&stream).write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn dispatch(handle: &MountHandle, request: Request) -> Response {
+    info!(?request, "control request");
+    let res = match request {
+        Request::Status => Ok(Response::Status(handle.status())),
+        Request::Flush => {
+            let stats = handle.flush();
+            Ok(Response::Flushed {
+                blobs: stats.blobs,
+                bytes: stats.bytes,
+            })
+        }
+        Request::Prefetch { digest, recursive } => cas::parse_digest(&digest)
+            .and_then(|d| handle.prefetch(&d.hash, d.size_bytes, recursive))
+            .map(|(blobs, bytes)| Response::Prefetched { blobs, bytes }),
+        Request::Swap { digest } => cas::parse_digest(&digest)
+            .and_then(|d| handle.swap_root(&d.hash, d.size_bytes))
+            .map(|invalidated| Response::Swapped {
+                digest,
+                invalidated: invalidated as u64,
+            }),
+        Request::Commit => handle.commit().map(|d| Response::Committed {
+            digest: format!("{}/{}", d.hash, d.size_bytes),
+        }),
+        Request::Unmount => handle.unmount().map(|_| Response::Unmounted),
+    };
+
+    res.unwrap_or_else(|e| Response::Error {
+        message: e.to_string(),
+    })
+}
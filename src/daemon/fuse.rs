@@ -1,38 +1,62 @@
-use libc::ENOSYS;
+use super::control;
+use super::metrics;
+use super::overlay::{self, Overlay};
+use super::prefetch::{PrefetchPolicy, Prefetcher};
+use super::profile::{self, Profile, Recorder};
+use super::signals;
 use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
 use cfs::cas;
-use fuser::consts::FOPEN_KEEP_CACHE;
+use cfs::cas::blocking::ClientOptions;
+use cfs::cas::blocking::{CacheStats, CHUNK_SIZE};
+use cfs::control::{MountStatus, Status};
+use cfs::metrics::FUSE_OP_DURATION;
+use fuser::consts::{FOPEN_CACHE_DIR, FOPEN_KEEP_CACHE, FUSE_DO_READDIRPLUS};
 use fuser::FileType;
 use fuser::{
-    Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyOpen, ReplyXattr, Request,
+    Filesystem, KernelConfig, MountOption, Notifier, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr,
+    Request, Session, TimeOrNow,
 };
-use std::cmp;
+use libc::ENOSYS;
+use prometheus::HistogramTimer;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
-use std::fs;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
 use std::ops::Add;
 use std::os::raw::c_int;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
+use tonic::Code;
+use tracing::{debug_span, error, info, warn};
 
 use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Directory as BazelDirectory;
 
 #[derive(Debug, Clone)]
 struct Inode {
     inode: u64,
+    /// the parent directory and the name under it, which make up the path of the inode
+    parent: u64,
+    name: OsString,
     attr: InodeAttr,
 }
 
 #[derive(Debug, Clone)]
 struct InodeAttr {
     //TODO: digest struct
+    /// digest of the lower layer content, empty when the entry only exists in the upper layer
     hash: String,
     size: i64,
     kind: FileKind,
     mode: u32,
+    /// whether the entry exists in the upper layer
+    upper: bool,
 }
 
 impl From<Inode> for fuser::FileAttr {
@@ -82,11 +106,23 @@ impl From<FileKind> for fuser::FileType {
     }
 }
 
-fn find_attr_by_name(d: BazelDirectory, name: &str) -> Option<InodeAttr> {
-    for f in d.files {
+impl From<fs::FileType> for FileKind {
+    fn from(file_type: fs::FileType) -> Self {
+        if file_type.is_dir() {
+            FileKind::Directory
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::File
+        }
+    }
+}
+
+fn find_attr_by_name(d: &BazelDirectory, name: &str) -> Option<InodeAttr> {
+    for f in &d.files {
         if f.name == name {
-            let digest = f.digest?;
-            let node_properties = f.node_properties;
+            let digest = f.digest.clone()?;
+            let node_properties = &f.node_properties;
             let mode = match node_properties {
                 Some(p) => match p.unix_mode {
                     Some(mode) => mode,
@@ -100,39 +136,86 @@ fn find_attr_by_name(d: BazelDirectory, name: &str) -> Option<InodeAttr> {
                 hash: digest.hash,
                 kind: FileKind::File,
                 mode: mode,
+                upper: false,
             });
         }
     }
 
-    for f in d.directories {
+    for f in &d.directories {
         if f.name == name {
-            let digest = f.digest?;
+            let digest = f.digest.clone()?;
             return Some(InodeAttr {
                 size: digest.size_bytes,
                 hash: digest.hash,
                 kind: FileKind::Directory,
                 mode: 0o0770,
+                upper: false,
             });
         }
     }
 
-    //for f in d.symlinks {
-    //    if f.name == name {
-    //        return Some(InodeAttr{
-    //            size: f.digest.size_bytes,
-    //            hash: f.digest.hash,
-    //            kind: FileKind::Symlink,
-    //        });
-    //    }
-    //}
+    // the target is read from the directory by readlink
+    for f in &d.symlinks {
+        if f.name == name {
+            return Some(InodeAttr {
+                size: f.target.len() as i64,
+                hash: String::new(),
+                kind: FileKind::Symlink,
+                mode: 0o0777,
+                upper: false,
+            });
+        }
+    }
     None
 }
 
-/// Cfs stands for CAS File System or content addressable file system
-/// that based on Bazel remote CAS service
-struct Cfs {
-    cas_client: cas::blocking::CacheClient,
+/// the attribute of an entry in the upper layer
+fn upper_attr(md: &fs::Metadata) -> InodeAttr {
+    InodeAttr {
+        hash: String::new(),
+        size: md.len() as i64,
+        kind: md.file_type().into(),
+        mode: md.permissions().mode() & 0o7777,
+        upper: true,
+    }
+}
+
+/// time the FUSE op till the timer is dropped
+fn op_timer(op: &str) -> HistogramTimer {
+    FUSE_OP_DURATION.with_label_values(&[op]).start_timer()
+}
+
+fn os_error(errno: c_int) -> anyhow::Error {
+    io::Error::from_raw_os_error(errno).into()
+}
+
+/// map the error to errno, errors not from the local filesystem or CAS are reported as EIO
+fn errno(e: &anyhow::Error) -> c_int {
+    if let Some(e) = e.downcast_ref::<cas::Error>() {
+        return cas_errno(e);
+    }
+    e.downcast_ref::<io::Error>()
+        .and_then(|e| e.raw_os_error())
+        .unwrap_or(libc::EIO)
+}
 
+/// map the CAS error to errno, throttled and dropped requests are reported
+/// as EAGAIN so that the readers could try again
+fn cas_errno(e: &cas::Error) -> c_int {
+    match e {
+        cas::Error::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        e => match e.code() {
+            Some(Code::NotFound) => libc::ENOENT,
+            Some(Code::PermissionDenied) | Some(Code::Unauthenticated) => libc::EACCES,
+            _ if e.is_retryable() => libc::EAGAIN,
+            _ => libc::EIO,
+        },
+    }
+}
+
+/// State of a mount. It is shared between the FUSE session and
+/// the control server
+struct MountState {
     /// CAS digest
     hash: String,
     size: i64,
@@ -141,133 +224,953 @@ struct Cfs {
     inodes: HashMap<u64, Inode>,
 
     /// directories map inode to the list of (name, inode) entries under the directory
-    /// TODO: use OsString
-    directories: HashMap<u64, HashMap<OsString, Inode>>,
+    directories: HashMap<u64, HashMap<OsString, u64>>,
+
+    /// the writable upper layer, the mount is read-only without it
+    overlay: Option<Overlay>,
 }
 
-impl Cfs {
-    fn new(hash: &str, size: i64) -> Result<Cfs> {
-        let cas_client = cas::blocking::CacheClient::new()?;
+/// DirEntry is an entry of a directory listing snapshot
+#[derive(Debug, Clone)]
+struct DirEntry {
+    ino: u64,
+    kind: FileKind,
+    name: OsString,
+}
+
+/// Invalidation is a kernel cache invalidation to be sent after the mount is updated
+#[derive(Debug)]
+enum Invalidation {
+    /// the attributes and the content of the inode
+    Inode(u64),
+    /// the entry under the parent inode
+    Entry(u64, OsString),
+}
 
-        Ok(Cfs {
-            cas_client: cas_client,
+impl MountState {
+    fn new(hash: &str, size: i64, overlay: Option<Overlay>) -> MountState {
+        let mut inodes = HashMap::new();
+        inodes.insert(
+            1,
+            Inode {
+                inode: 1,
+                parent: 1,
+                name: OsString::new(),
+                attr: InodeAttr {
+                    hash: hash.to_string(),
+                    size,
+                    kind: FileKind::Directory,
+                    mode: 0o0770,
+                    upper: overlay.is_some(),
+                },
+            },
+        );
+        MountState {
             hash: hash.to_string(),
-            size: size,
-            inodes: HashMap::new(),
+            size,
+            inodes,
             directories: HashMap::new(),
-        })
+            overlay,
+        }
     }
 
     fn next_inode_id(&self) -> u64 {
         self.inodes.len() as u64 + 1
     }
 
-    /// get the contents of a directory as tree map
-    /// tree map is required for readdir when buf is full and readdir is called
-    /// with offset > 0. tree map guarantees the order when elements are skipped
-    fn get_directory_content(&mut self, inode: u64) -> Result<BTreeMap<String, FileKind>> {
+    /// the path of the inode relative to the mount root
+    fn path(&self, ino: u64) -> PathBuf {
+        let mut names = vec![];
+        let mut ino = ino;
+        while ino != 1 {
+            match self.inodes.get(&ino) {
+                Some(inode) => {
+                    names.push(inode.name.clone());
+                    ino = inode.parent;
+                }
+                None => break,
+            }
+        }
+        names.iter().rev().collect()
+    }
+
+    fn entry(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.directories
+            .get(&parent)
+            .and_then(|entries| entries.get(name))
+            .copied()
+    }
+
+    /// allocate a new inode for the entry under the parent
+    fn add_entry(&mut self, parent: u64, name: &OsStr, attr: InodeAttr) -> Inode {
+        let inode = Inode {
+            inode: self.next_inode_id(),
+            parent,
+            name: name.to_os_string(),
+            attr,
+        };
+        self.inodes.insert(inode.inode, inode.clone());
+        self.directories
+            .entry(parent)
+            .or_insert_with(HashMap::new)
+            .insert(name.to_os_string(), inode.inode);
+        inode
+    }
+
+    fn remove_entry(&mut self, parent: u64, name: &OsStr) {
+        if let Some(entries) = self.directories.get_mut(&parent) {
+            entries.remove(name);
+        }
+    }
+
+    /// get the inode with the attributes of the upper layer entry refreshed
+    fn attr(&mut self, ino: u64) -> Option<Inode> {
+        let path = self.path(ino);
+        let inode = self.inodes.get_mut(&ino)?;
+        if inode.attr.upper && inode.attr.kind != FileKind::Directory {
+            if let Some(md) = self.overlay.as_ref().and_then(|o| o.metadata(&path)) {
+                inode.attr.size = md.len() as i64;
+                inode.attr.mode = md.permissions().mode() & 0o7777;
+            }
+        }
+        Some(inode.clone())
+    }
+
+    /// the directory of the lower layer, None when the directory only exists
+    /// in the upper layer or hides the lower layer
+    fn lower_dir(
+        &self,
+        cas_client: &cas::blocking::CacheClient,
+        ino: u64,
+    ) -> Result<Option<Arc<BazelDirectory>>> {
         let inode = self
             .inodes
-            .get(&inode)
+            .get(&ino)
             .ok_or(anyhow::Error::msg("inode not found"))?;
+        if inode.attr.kind != FileKind::Directory || inode.attr.hash.is_empty() {
+            return Ok(None);
+        }
+        if let Some(overlay) = &self.overlay {
+            if overlay.is_opaque(&self.path(ino)) {
+                return Ok(None);
+            }
+        }
+        cas_client
+            .read_dir(&inode.attr.hash, inode.attr.size)
+            .map(Some)
+    }
 
-        let dir = self.cas_client.get_dir(&inode.attr.hash, inode.attr.size)?;
-        let mut entries = BTreeMap::new();
-        for f in dir.files {
-            entries.insert(f.name, FileKind::File);
+    fn lower_attr(
+        &self,
+        cas_client: &cas::blocking::CacheClient,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<Option<InodeAttr>> {
+        let dir = self.lower_dir(cas_client, parent)?;
+        Ok(match (dir, name.to_str()) {
+            (Some(dir), Some(name)) => find_attr_by_name(&dir, name),
+            _ => None,
+        })
+    }
+
+    /// resolve the entry under the parent from the merged view of both layers
+    fn resolve(
+        &self,
+        cas_client: &cas::blocking::CacheClient,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<Option<InodeAttr>> {
+        // TODOs: fix directory node properties
+        let lower = self.lower_attr(cas_client, parent, name)?;
+        let overlay = match &self.overlay {
+            Some(overlay) => overlay,
+            None => return Ok(lower),
+        };
+        if Overlay::is_reserved(name) {
+            return Ok(None);
         }
-        for f in dir.directories {
-            entries.insert(f.name, FileKind::Directory);
+
+        let rel = self.path(parent).join(name);
+        if overlay.is_whiteout(&rel) {
+            return Ok(None);
         }
-        for f in dir.symlinks {
-            entries.insert(f.name, FileKind::Symlink);
+        match overlay.metadata(&rel) {
+            Some(md) => {
+                let attr = upper_attr(&md);
+                match lower {
+                    // merged directory keeps the lower digest for the entries
+                    // that are not in the upper layer
+                    Some(lower) if attr.kind == FileKind::Directory && lower.kind == attr.kind => {
+                        Ok(Some(InodeAttr {
+                            upper: true,
+                            ..lower
+                        }))
+                    }
+                    _ => Ok(Some(attr)),
+                }
+            }
+            None => Ok(lower),
+        }
+    }
+
+    /// get the contents of a directory as tree map
+    /// tree map is required for readdir when buf is full and readdir is called
+    /// with offset > 0. tree map guarantees the order when elements are skipped
+    fn get_directory_content(
+        &self,
+        cas_client: &cas::blocking::CacheClient,
+        inode: u64,
+    ) -> Result<BTreeMap<String, FileKind>> {
+        let mut entries = BTreeMap::new();
+        if let Some(dir) = self.lower_dir(cas_client, inode)? {
+            for f in &dir.files {
+                entries.insert(f.name.clone(), FileKind::File);
+            }
+            for f in &dir.directories {
+                entries.insert(f.name.clone(), FileKind::Directory);
+            }
+            for f in &dir.symlinks {
+                entries.insert(f.name.clone(), FileKind::Symlink);
+            }
+        }
+
+        if let Some(overlay) = &self.overlay {
+            let (upper, whiteouts) = overlay.read_dir(&self.path(inode))?;
+            for name in whiteouts {
+                entries.remove(&*name.to_string_lossy());
+            }
+            for (name, md) in upper {
+                entries.insert(name.to_string_lossy().to_string(), md.file_type().into());
+            }
         }
         Ok(entries)
     }
-}
 
-impl Filesystem for Cfs {
-    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
-        self.inodes.insert(
-            1,
-            Inode {
-                inode: 1,
-                attr: InodeAttr {
-                    hash: self.hash.to_string(),
-                    size: self.size,
-                    kind: FileKind::Directory,
-                    mode: 0o0770,
-                },
+    /// snapshot the merged entries of the directory including `.` and `..`.
+    /// Inodes are allocated for the entries not being looked up yet so that
+    /// readdir reports the same inode numbers as lookup
+    fn list(&mut self, cas_client: &cas::blocking::CacheClient, ino: u64) -> Result<Vec<DirEntry>> {
+        let parent = self
+            .inodes
+            .get(&ino)
+            .map(|inode| inode.parent)
+            .ok_or_else(|| os_error(libc::ENOENT))?;
+        let mut entries = vec![
+            DirEntry {
+                ino,
+                kind: FileKind::Directory,
+                name: OsString::from("."),
             },
-        );
+            DirEntry {
+                ino: parent,
+                kind: FileKind::Directory,
+                name: OsString::from(".."),
+            },
+        ];
+
+        for (name, kind) in self.get_directory_content(cas_client, ino)? {
+            let name = OsString::from(name);
+            let child = match self.entry(ino, &name) {
+                Some(child) => child,
+                None => match self.resolve(cas_client, ino, &name)? {
+                    Some(attr) => self.add_entry(ino, &name, attr).inode,
+                    None => continue,
+                },
+            };
+            entries.push(DirEntry {
+                ino: child,
+                kind,
+                name,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// the target of the symlink, from the upper layer or the lower directory
+    fn link_target(&self, cas_client: &cas::blocking::CacheClient, ino: u64) -> Result<OsString> {
+        let inode = self
+            .inodes
+            .get(&ino)
+            .ok_or_else(|| os_error(libc::ENOENT))?;
+        if inode.attr.kind != FileKind::Symlink {
+            return Err(os_error(libc::EINVAL));
+        }
+        if inode.attr.upper {
+            let overlay = self
+                .overlay
+                .as_ref()
+                .ok_or_else(|| os_error(libc::ENOENT))?;
+            return Ok(fs::read_link(overlay.path(&self.path(ino)))?.into_os_string());
+        }
+        let dir = self.lower_dir(cas_client, inode.parent)?;
+        dir.and_then(|dir| {
+            dir.symlinks
+                .iter()
+                .find(|s| inode.name.to_str() == Some(s.name.as_str()))
+                .map(|s| OsString::from(&s.target))
+        })
+        .ok_or_else(|| os_error(libc::ENOENT))
+    }
+
+    /// create the directory in the upper layer along with its parents. The
+    /// directories copied up keep the mode of the lower layer, so that the
+    /// commit does not record the default mode over it
+    fn ensure_upper_dir(
+        &mut self,
+        cas_client: &cas::blocking::CacheClient,
+        ino: u64,
+    ) -> Result<()> {
+        let overlay = self.overlay.as_ref().ok_or_else(|| os_error(libc::EROFS))?;
+
+        // from the inode up to the root
+        let mut dirs = vec![];
+        let mut next = ino;
+        while let Some(inode) = self.inodes.get(&next) {
+            dirs.push(next);
+            if next == 1 {
+                break;
+            }
+            next = inode.parent;
+        }
+
+        for &dir in dirs.iter().rev() {
+            let path = overlay.path(&self.path(dir));
+            if fs::symlink_metadata(&path).is_ok() {
+                continue;
+            }
+            fs::create_dir(&path)?;
+            let mode = self
+                .lower_dir(cas_client, dir)?
+                .and_then(|d| d.node_properties.as_ref().and_then(|p| p.unix_mode));
+            if let Some(mode) = mode {
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+
+        for dir in dirs {
+            if let Some(inode) = self.inodes.get_mut(&dir) {
+                inode.attr.upper = true;
+            }
+        }
         Ok(())
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        println!("lookup: parent {} name {:?}", parent, name);
+    /// copy the entry from the lower layer to the upper layer before it is modified
+    fn copy_up(&mut self, cas_client: &cas::blocking::CacheClient, ino: u64) -> Result<()> {
+        let inode = self
+            .inodes
+            .get(&ino)
+            .cloned()
+            .ok_or_else(|| os_error(libc::ENOENT))?;
+        if inode.attr.upper {
+            return Ok(());
+        }
+        if inode.attr.kind == FileKind::Directory {
+            return self.ensure_upper_dir(cas_client, ino);
+        }
+        if inode.attr.kind == FileKind::Symlink {
+            return Err(os_error(libc::EPERM));
+        }
 
-        if let Some(entries) = self.directories.get(&parent) {
-            if let Some(inode) = entries.get(&name.to_os_string()) {
-                reply.entry(&Duration::new(60, 0), &inode.clone().into(), 0);
-                return;
+        self.ensure_upper_dir(cas_client, inode.parent)?;
+        let path = match &self.overlay {
+            Some(overlay) => overlay.path(&self.path(ino)),
+            None => return Err(os_error(libc::EROFS)),
+        };
+        // streamed by chunks bypassing the cache, so that a large file is
+        // neither held in memory nor left cached once it is in the upper layer
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let size = inode.attr.size as u64;
+        let mut offset = 0;
+        while offset < size {
+            let limit = CHUNK_SIZE.min(size - offset);
+            let chunk = cas_client.fetch_range(&inode.attr.hash, inode.attr.size, offset, limit)?;
+            if chunk.is_empty() {
+                return Err(os_error(libc::EIO));
             }
+            file.write_all(&chunk)?;
+            offset += chunk.len() as u64;
         }
-        let inode = match self.inodes.get(&parent) {
-            Some(inode) => inode,
+        fs::set_permissions(&path, fs::Permissions::from_mode(inode.attr.mode & 0o7777))?;
+
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.attr.hash = String::new();
+            inode.attr.upper = true;
+        }
+        Ok(())
+    }
+
+    /// create a file or a directory in the upper layer
+    fn create(
+        &mut self,
+        cas_client: &cas::blocking::CacheClient,
+        parent: u64,
+        name: &OsStr,
+        kind: FileKind,
+        mode: u32,
+    ) -> Result<Inode> {
+        if Overlay::is_reserved(name) {
+            return Err(os_error(libc::EINVAL));
+        }
+        self.ensure_upper_dir(cas_client, parent)?;
+
+        let rel = self.path(parent).join(name);
+        let overlay = self.overlay.as_ref().ok_or_else(|| os_error(libc::EROFS))?;
+        let path = overlay.path(&rel);
+        // the upper layer takes over the entry removed from the lower layer
+        let whiteout = overlay.remove_whiteout(&rel)?;
+        match kind {
+            FileKind::File => {
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(mode & 0o7777)
+                    .open(&path)?;
+            }
+            FileKind::Directory => {
+                DirBuilder::new().mode(mode & 0o7777).create(&path)?;
+                if whiteout {
+                    overlay.set_opaque(&rel)?;
+                }
+            }
+            FileKind::Symlink => return Err(os_error(libc::EPERM)),
+        }
+
+        let md = fs::symlink_metadata(&path)?;
+        Ok(self.add_entry(parent, name, upper_attr(&md)))
+    }
+
+    /// remove a file or an empty directory from the merged view
+    fn remove(
+        &mut self,
+        cas_client: &cas::blocking::CacheClient,
+        parent: u64,
+        name: &OsStr,
+        is_dir: bool,
+    ) -> Result<()> {
+        if self.overlay.is_none() {
+            return Err(os_error(libc::EROFS));
+        }
+        let attr = self
+            .resolve(cas_client, parent, name)?
+            .ok_or_else(|| os_error(libc::ENOENT))?;
+        if is_dir && attr.kind != FileKind::Directory {
+            return Err(os_error(libc::ENOTDIR));
+        }
+        if !is_dir && attr.kind == FileKind::Directory {
+            return Err(os_error(libc::EISDIR));
+        }
+        if is_dir {
+            let ino = match self.entry(parent, name) {
+                Some(ino) => ino,
+                None => self.add_entry(parent, name, attr.clone()).inode,
+            };
+            if !self.get_directory_content(cas_client, ino)?.is_empty() {
+                return Err(os_error(libc::ENOTEMPTY));
+            }
+        }
+
+        let in_lower = self.lower_attr(cas_client, parent, name)?.is_some();
+        self.ensure_upper_dir(cas_client, parent)?;
+        let rel = self.path(parent).join(name);
+        if let Some(overlay) = &self.overlay {
+            if attr.upper {
+                let path = overlay.path(&rel);
+                if is_dir {
+                    // only the whiteouts and the opaque marker are left
+                    fs::remove_dir_all(path)?;
+                } else {
+                    fs::remove_file(path)?;
+                }
+            }
+            if in_lower {
+                overlay.add_whiteout(&rel)?;
+            }
+        }
+        self.remove_entry(parent, name);
+        Ok(())
+    }
+
+    /// move the entry within the merged view. Like overlayfs, directories
+    /// from the lower layer cannot be moved and EXDEV makes `mv` fall back to copy
+    fn rename(
+        &mut self,
+        cas_client: &cas::blocking::CacheClient,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<()> {
+        if self.overlay.is_none() {
+            return Err(os_error(libc::EROFS));
+        }
+        if Overlay::is_reserved(newname) {
+            return Err(os_error(libc::EINVAL));
+        }
+        let ino = match self.entry(parent, name) {
+            Some(ino) => ino,
             None => {
-                reply.error(libc::ENOENT);
-                return;
+                let attr = self
+                    .resolve(cas_client, parent, name)?
+                    .ok_or_else(|| os_error(libc::ENOENT))?;
+                self.add_entry(parent, name, attr).inode
             }
         };
+        let in_lower = self.lower_attr(cas_client, parent, name)?.is_some();
+        let is_dir = self
+            .inodes
+            .get(&ino)
+            .map(|inode| inode.attr.kind == FileKind::Directory)
+            .unwrap_or(false);
+        if is_dir && in_lower {
+            return Err(os_error(libc::EXDEV));
+        }
 
-        let dir = match self.cas_client.get_dir(&inode.attr.hash, inode.attr.size) {
-            Ok(dir) => dir,
-            Err(_) => {
-                reply.error(libc::ENOSYS);
-                return;
+        self.copy_up(cas_client, ino)?;
+        self.ensure_upper_dir(cas_client, newparent)?;
+        let from = self.path(ino);
+        let to = self.path(newparent).join(newname);
+        if let Some(overlay) = &self.overlay {
+            overlay.remove_whiteout(&to)?;
+            fs::rename(overlay.path(&from), overlay.path(&to))?;
+            if in_lower {
+                overlay.add_whiteout(&from)?;
             }
-        };
+        }
 
-        let next_inode_id = self.next_inode_id();
-        // TODOs: fix directory node properties
-        let name_str = match name.to_str() {
-            Some(name) => name,
-            None => {
-                //TODO: better error code
-                reply.error(libc::ENOSYS);
-                return;
+        self.remove_entry(parent, name);
+        self.remove_entry(newparent, newname);
+        self.directories
+            .entry(newparent)
+            .or_insert_with(HashMap::new)
+            .insert(newname.to_os_string(), ino);
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.parent = newparent;
+            inode.name = newname.to_os_string();
+        }
+        Ok(())
+    }
+
+    fn set_attr(
+        &mut self,
+        cas_client: &cas::blocking::CacheClient,
+        ino: u64,
+        mode: Option<u32>,
+        size: Option<u64>,
+    ) -> Result<Inode> {
+        if self.overlay.is_none() {
+            return Err(os_error(libc::EROFS));
+        }
+        self.copy_up(cas_client, ino)?;
+        if let Some(overlay) = &self.overlay {
+            let path = overlay.path(&self.path(ino));
+            if let Some(size) = size {
+                OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+            }
+            if let Some(mode) = mode {
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
             }
+        }
+        if let (Some(mode), Some(inode)) = (mode, self.inodes.get_mut(&ino)) {
+            inode.attr.mode = mode & 0o7777;
+        }
+        self.attr(ino).ok_or_else(|| os_error(libc::ENOENT))
+    }
+
+    /// collect the digests of the changed directories that remap descends into
+    /// and are not fetched into `dirs` yet, so that they can be fetched without
+    /// holding the lock. The directories under them are found once they are fetched
+    fn missing_dirs(
+        &self,
+        parent: u64,
+        dir: &BazelDirectory,
+        dirs: &HashMap<String, Arc<BazelDirectory>>,
+        missing: &mut Vec<Digest>,
+    ) {
+        let entries = match self.directories.get(&parent) {
+            Some(entries) => entries,
+            None => return,
         };
-        let node_attr = match find_attr_by_name(dir, name_str) {
-            Some(attr) => attr,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
+
+        for (name, ino) in entries {
+            let child = match self.inodes.get(ino) {
+                Some(child) if child.attr.kind == FileKind::Directory => child,
+                _ => continue,
+            };
+            let attr = match name.to_str().and_then(|n| find_attr_by_name(dir, n)) {
+                Some(attr) if attr.kind == FileKind::Directory && attr.hash != child.attr.hash => {
+                    attr
+                }
+                _ => continue,
+            };
+            match dirs.get(&attr.hash) {
+                Some(sub_dir) => self.missing_dirs(*ino, sub_dir, dirs, missing),
+                None => missing.push(Digest {
+                    hash: attr.hash,
+                    size_bytes: attr.size,
+                }),
             }
+        }
+    }
+
+    /// remap the entries looked up under the `parent` inode to the new directory
+    ///
+    /// entries with identical content keep their inodes. Changed directories keep their
+    /// inodes and are remapped recursively with the sub directories fetched into `dirs`.
+    /// Removed entries, changed files and the directories not fetched are dropped from
+    /// the entries so that the next lookup allocates a new inode, while the old inode
+    /// is kept in the inode table for the files being opened. Files in the upper layer
+    /// are not affected by the lower layer.
+    fn remap(
+        &mut self,
+        parent: u64,
+        dir: &BazelDirectory,
+        dirs: &HashMap<String, Arc<BazelDirectory>>,
+        invalidations: &mut Vec<Invalidation>,
+    ) {
+        let entries = match self.directories.get(&parent) {
+            Some(entries) => entries.clone(),
+            None => return,
         };
-        let inode = Inode {
-            inode: next_inode_id,
-            attr: node_attr,
+
+        for (name, ino) in entries {
+            let child = match self.inodes.get(&ino) {
+                Some(child) => child.clone(),
+                None => continue,
+            };
+            if child.attr.upper && child.attr.kind != FileKind::Directory {
+                continue;
+            }
+
+            let attr = name.to_str().and_then(|n| find_attr_by_name(dir, n));
+            let sub_dir = attr.as_ref().and_then(|attr| dirs.get(&attr.hash));
+            match (attr, sub_dir) {
+                (Some(attr), _) if attr.hash == child.attr.hash && attr.kind == child.attr.kind => {
+                    if attr.mode != child.attr.mode {
+                        if let Some(inode) = self.inodes.get_mut(&ino) {
+                            inode.attr.mode = attr.mode;
+                        }
+                        invalidations.push(Invalidation::Inode(ino));
+                    }
+                }
+                (Some(attr), Some(sub_dir))
+                    if attr.kind == FileKind::Directory
+                        && child.attr.kind == FileKind::Directory =>
+                {
+                    if let Some(inode) = self.inodes.get_mut(&ino) {
+                        inode.attr = InodeAttr {
+                            upper: child.attr.upper,
+                            ..attr
+                        };
+                    }
+                    self.remap(ino, sub_dir, dirs, invalidations);
+                    invalidations.push(Invalidation::Inode(ino));
+                }
+                _ => {
+                    self.remove_entry(parent, &name);
+                    invalidations.push(Invalidation::Entry(parent, name));
+                }
+            }
+        }
+    }
+}
+
+/// Cfs stands for CAS File System or content addressable file system
+/// that based on Bazel remote CAS service
+struct Cfs {
+    cas_client: cas::blocking::CacheClient,
+
+    state: Arc<Mutex<MountState>>,
+
+    prefetcher: Prefetcher,
+
+    /// where the last read of each inode ended, a read starting there
+    /// means the file is being read sequentially
+    read_ends: HashMap<u64, u64>,
+
+    /// records the blobs being read when profiling is enabled
+    recorder: Option<Arc<Recorder>>,
+
+    /// directory listing snapshots of the open directory handles
+    dir_handles: HashMap<u64, Arc<Vec<DirEntry>>>,
+
+    next_fh: u64,
+}
+
+impl Cfs {
+    fn new(
+        cas_client: cas::blocking::CacheClient,
+        state: Arc<Mutex<MountState>>,
+        prefetcher: Prefetcher,
+        recorder: Option<Arc<Recorder>>,
+    ) -> Cfs {
+        Cfs {
+            cas_client,
+            state,
+            prefetcher,
+            read_ends: HashMap::new(),
+            recorder,
+            dir_handles: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    /// the listing snapshot of the handle, or a fresh listing when the
+    /// directory was not opened through opendir
+    fn dir_entries(&mut self, ino: u64, fh: u64) -> Result<Arc<Vec<DirEntry>>> {
+        if let Some(entries) = self.dir_handles.get(&fh) {
+            return Ok(entries.clone());
+        }
+        let mut state = self.state.lock().unwrap();
+        state.list(&self.cas_client, ino).map(Arc::new)
+    }
+}
+
+/// MountHandle controls a running mount from outside of the FUSE session
+#[derive(Clone)]
+pub struct MountHandle {
+    mountpoint: String,
+
+    cas_client: cas::blocking::CacheClient,
+
+    state: Arc<Mutex<MountState>>,
+
+    notifier: Notifier,
+}
+
+impl MountHandle {
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        Status {
+            mounts: vec![MountStatus {
+                mountpoint: self.mountpoint.clone(),
+                digest: format!("{}/{}", state.hash, state.size),
+            }],
+            cache: self.cas_client.stats(),
+            inodes: state.inodes.len() as u64,
+            directories: state.directories.len() as u64,
+        }
+    }
+
+    pub fn flush(&self) -> CacheStats {
+        self.cas_client.flush()
+    }
+
+    /// fetch the blob into the cache, returns the number of blobs and bytes fetched
+    pub fn prefetch(&self, hash: &str, size: i64, recursive: bool) -> Result<(u64, u64)> {
+        if !recursive {
+            let blob = self.cas_client.read_blob(hash, size)?;
+            return Ok((1, blob.len() as u64));
+        }
+
+        let dir = self.cas_client.get_dir(hash, size)?;
+        let mut blobs = 1;
+        let mut bytes = size as u64;
+
+        // small files in batches, large files by chunks as they are read
+        let mut small = vec![];
+        for f in dir.files {
+            if let Some(digest) = f.digest {
+                if digest.size_bytes as u64 <= CHUNK_SIZE {
+                    small.push(digest);
+                    continue;
+                }
+                for index in 0..(digest.size_bytes as u64 + CHUNK_SIZE - 1) / CHUNK_SIZE {
+                    let chunk =
+                        self.cas_client
+                            .read_chunk(&digest.hash, digest.size_bytes, index)?;
+                    bytes += chunk.len() as u64;
+                }
+                blobs += 1;
+            }
+        }
+        for blob in self.cas_client.read_blobs(&small)?.values() {
+            blobs += 1;
+            bytes += blob.len() as u64;
+        }
+        for d in dir.directories {
+            if let Some(digest) = d.digest {
+                let (b, s) = self.prefetch(&digest.hash, digest.size_bytes, true)?;
+                blobs += b;
+                bytes += s;
+            }
+        }
+        Ok((blobs, bytes))
+    }
+
+    /// swap the root digest of the live mount, returns the number of kernel cache
+    /// entries being invalidated
+    pub fn swap_root(&self, hash: &str, size: i64) -> Result<usize> {
+        if self.is_root(hash, size) {
+            return Ok(0);
+        }
+
+        // the directories are fetched without holding the lock, so that the
+        // mount keeps serving the FUSE ops meanwhile. Each round fetches the
+        // changed directories one level deeper than the previous one
+        let root = self.cas_client.read_dir(hash, size)?;
+        let mut dirs = HashMap::new();
+        loop {
+            let mut missing = vec![];
+            self.state
+                .lock()
+                .unwrap()
+                .missing_dirs(1, &root, &dirs, &mut missing);
+            if missing.is_empty() {
+                break;
+            }
+            for digest in missing {
+                let dir = self.cas_client.read_dir(&digest.hash, digest.size_bytes)?;
+                dirs.insert(digest.hash, dir);
+            }
+        }
+
+        let mut invalidations = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            // swapped to the same root by another request meanwhile
+            if state.hash == hash && state.size == size {
+                return Ok(0);
+            }
+            state.remap(1, &root, &dirs, &mut invalidations);
+            state.hash = hash.to_string();
+            state.size = size;
+            if let Some(inode) = state.inodes.get_mut(&1) {
+                inode.attr.hash = hash.to_string();
+                inode.attr.size = size;
+            }
+            invalidations.push(Invalidation::Inode(1));
+        }
+
+        // the kernel may call back into the filesystem while handling the
+        // notifications, so they have to be sent without holding the state lock
+        for invalidation in &invalidations {
+            let res = match invalidation {
+                Invalidation::Inode(ino) => self.notifier.inval_inode(*ino, 0, 0),
+                Invalidation::Entry(parent, name) => self.notifier.inval_entry(*parent, name),
+            };
+            if let Err(e) = res {
+                warn!(?invalidation, error = %e, "failed to invalidate");
+            }
+        }
+        Ok(invalidations.len())
+    }
+
+    fn is_root(&self, hash: &str, size: i64) -> bool {
+        let state = self.state.lock().unwrap();
+        state.hash == hash && state.size == size
+    }
+
+    /// hash the merged view of the mount and upload it as a new tree,
+    /// returns the digest of the new root directory
+    pub fn commit(&self) -> Result<Digest> {
+        // the tree is hashed and uploaded without holding the lock, so that
+        // the mount keeps serving the FUSE ops meanwhile. Writes made during
+        // the commit may or may not be part of it
+        let (overlay, hash, size) = {
+            let state = self.state.lock().unwrap();
+            let overlay = state.overlay.clone().ok_or(anyhow::Error::msg(
+                "the mount is read-only, start cfsd with --upper_dir to enable writes",
+            ))?;
+            (overlay, state.hash.clone(), state.size)
         };
-        reply.entry(&Duration::new(60, 0), &inode.clone().into(), 0);
-        self.inodes.insert(next_inode_id as u64, inode.clone());
-        match self.directories.get_mut(&parent) {
-            Some(entries) => {
-                entries.insert(name.to_os_string(), inode);
+        overlay::commit(&overlay, &self.cas_client, &hash, size)
+    }
+
+    /// unmount the filesystem, which makes the FUSE session exit
+    pub fn unmount(&self) -> Result<()> {
+        self.fusermount(&["-u"])
+    }
+
+    /// lazy unmount, the mount is detached even when it is busy and goes
+    /// away once the files open in it are closed
+    pub fn detach(&self) -> Result<()> {
+        self.fusermount(&["-u", "-z"])
+    }
+
+    /// connect to CAS again, to pick up a rotated CA certificate or token
+    pub fn reload(&self) -> Result<()> {
+        self.cas_client.reconnect().map_err(|e| e.into())
+    }
+
+    fn fusermount(&self, args: &[&str]) -> Result<()> {
+        let output = Command::new("fusermount")
+            .args(args)
+            .arg(&self.mountpoint)
+            .output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "failed to unmount {}: {}",
+                self.mountpoint,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+impl Filesystem for Cfs {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        // readdirplus returns the attributes along with the entries so that
+        // `ls -l` does not need a lookup for each entry
+        if let Err(unsupported) = config.add_capabilities(FUSE_DO_READDIRPLUS) {
+            warn!("kernel does not support capabilities {:#x}", unsupported);
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _span = debug_span!("lookup", parent, ?name).entered();
+        let _timer = op_timer("lookup");
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(ino) = state.entry(parent, name) {
+            if let Some(inode) = state.attr(ino) {
+                reply.entry(&Duration::new(60, 0), &inode.into(), 0);
+                return;
             }
-            None => {
-                let mut entries = HashMap::new();
-                entries.insert(name.to_os_string(), inode);
-                self.directories.insert(parent, entries);
+        }
+        if !state.inodes.contains_key(&parent) {
+            reply.error(libc::ENOENT);
+            return;
+        }
+
+        let node_attr = match state.resolve(&self.cas_client, parent, name) {
+            Ok(Some(attr)) => attr,
+            Ok(None) => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to lookup");
+                reply.error(errno(&e));
+                return;
             }
         };
+        if let (Some(recorder), Some(dir)) = (&self.recorder, state.inodes.get(&parent)) {
+            if !dir.attr.hash.is_empty() {
+                recorder.record(&dir.attr.hash, dir.attr.size, 0);
+            }
+        }
+        let inode = state.add_entry(parent, name, node_attr);
+        // the subtree was beyond the reach of the previous preloads
+        if inode.attr.kind == FileKind::Directory && !inode.attr.hash.is_empty() {
+            self.prefetcher.tree(&inode.attr.hash, inode.attr.size);
+        }
+        reply.entry(&Duration::new(60, 0), &inode.into(), 0);
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        println!("getattr(ino={})", ino);
-        match self.inodes.get(&ino) {
+        let _span = debug_span!("getattr", ino).entered();
+        let _timer = op_timer("getattr");
+        match self.state.lock().unwrap().attr(ino) {
             Some(inode) => {
-                reply.attr(&Duration::new(60, 0), &inode.clone().into());
+                reply.attr(&Duration::new(60, 0), &inode.into());
             }
             None => {
                 reply.error(libc::ENOENT);
@@ -276,16 +1179,148 @@ impl Filesystem for Cfs {
         }
     }
 
-    // TODO: how to directory cache
-    // fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-    //     println!("opendir(ino={} flags={})", ino, flags);
-    //     reply.opened(0, FOPEN_CACHE_DIR);
-    // }
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let _span = debug_span!("readlink", ino).entered();
+        let _timer = op_timer("readlink");
+        let res = self
+            .state
+            .lock()
+            .unwrap()
+            .link_target(&self.cas_client, ino);
+        match res {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _span = debug_span!("setattr", ino, ?mode, ?size).entered();
+        let _timer = op_timer("setattr");
+        let mut state = self.state.lock().unwrap();
+        let res = if mode.is_none() && size.is_none() {
+            state.attr(ino).ok_or_else(|| os_error(libc::ENOENT))
+        } else {
+            state.set_attr(&self.cas_client, ino, mode, size)
+        };
+        match res {
+            Ok(inode) => reply.attr(&Duration::new(60, 0), &inode.into()),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    // Snapshot the entries at opendir so that the readdir calls of the same
+    // handle see a consistent listing, and let the kernel cache the listing
+    // across opens. The kernel drops the cache when the directory changes
+    // through the mount, and swap invalidates the directory inodes
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _span = debug_span!("opendir", ino, flags).entered();
+        let _timer = op_timer("opendir");
+        let entries = {
+            let mut state = self.state.lock().unwrap();
+            match state.list(&self.cas_client, ino) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    reply.error(errno(&e));
+                    return;
+                }
+            }
+        };
+
+        // small files are likely to be read right after listing, eg. sources
+        let max_size = self.prefetcher.policy().listing_max_size;
+        if max_size > 0 {
+            let state = self.state.lock().unwrap();
+            let mut digests = vec![];
+            for entry in entries.iter().skip(2) {
+                if let Some(inode) = state.inodes.get(&entry.ino) {
+                    let attr = &inode.attr;
+                    if attr.kind == FileKind::File && !attr.upper && attr.size as u64 <= max_size {
+                        digests.push(Digest {
+                            hash: attr.hash.clone(),
+                            size_bytes: attr.size,
+                        });
+                    }
+                }
+            }
+            self.prefetcher.files(digests);
+        }
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dir_handles.insert(fh, Arc::new(entries));
+        reply.opened(fh, FOPEN_CACHE_DIR | FOPEN_KEEP_CACHE);
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
     // Set the keep_cache flag to enable caching the file at page cache for subsequent file opens
     // Otherwise, the files will be read from fuse each time it's opened, this performance degradation
     // is pretty notice when execute binary commands
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        reply.opened(0, FOPEN_KEEP_CACHE);
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let writable = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        if !writable {
+            let max_size = self.prefetcher.policy().open_max_size;
+            if let Some(inode) = self.state.lock().unwrap().inodes.get(&ino) {
+                if !inode.attr.upper && inode.attr.size as u64 <= max_size {
+                    self.prefetcher.file(&inode.attr.hash, inode.attr.size);
+                }
+            }
+            reply.opened(0, FOPEN_KEEP_CACHE);
+            return;
+        }
+
+        // the file is copied to the upper layer on the first open for write
+        let mut state = self.state.lock().unwrap();
+        if state.overlay.is_none() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        match state.copy_up(&self.cas_client, ino) {
+            Ok(_) => reply.opened(0, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        // a later open reads the file from the start again
+        self.read_ends.remove(&ino);
+        reply.ok();
     }
 
     fn readdir(
@@ -296,25 +1331,63 @@ impl Filesystem for Cfs {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        println!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        let _span = debug_span!("readdir", ino, fh, offset).entered();
+        let _timer = op_timer("readdir");
         assert!(offset >= 0);
 
-        let entries = match self.get_directory_content(ino) {
+        let entries = match self.dir_entries(ino, fh) {
             Ok(entries) => entries,
-            Err(_) => {
-                reply.error(libc::ENOSYS);
+            Err(e) => {
+                reply.error(errno(&e));
                 return;
             }
         };
 
-        for (index, entry) in entries.iter().skip(offset as usize).enumerate() {
-            let (name, file_type) = entry;
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let buffer_full: bool =
+                reply.add(entry.ino, index as i64 + 1, entry.kind.into(), &entry.name);
+
+            if buffer_full {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let _span = debug_span!("readdirplus", ino, fh, offset).entered();
+        let _timer = op_timer("readdirplus");
+        assert!(offset >= 0);
+
+        let entries = match self.dir_entries(ino, fh) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        };
 
+        let mut state = self.state.lock().unwrap();
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let inode = match state.attr(entry.ino) {
+                Some(inode) => inode,
+                None => continue,
+            };
             let buffer_full: bool = reply.add(
-                ino,
-                offset + index as i64 + 1,
-                (*file_type).into(),
-                OsStr::from_bytes(name.as_bytes()),
+                entry.ino,
+                index as i64 + 1,
+                &entry.name,
+                &Duration::new(60, 0),
+                &inode.into(),
+                0,
             );
 
             if buffer_full {
@@ -336,28 +1409,190 @@ impl Filesystem for Cfs {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        println!(
-            "read (inode = {}, fh = {}, offset = {}, size = {})",
-            inode, fh, offset, size
-        );
-        let inode = match self.inodes.get(&inode) {
-            Some(inode) => inode,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
+        let _span = debug_span!("read", ino = inode, fh, offset, size).entered();
+        let _timer = op_timer("read");
+        let (inode, upper_path) = {
+            let state = self.state.lock().unwrap();
+            match state.inodes.get(&inode) {
+                Some(node) => {
+                    let upper_path = match &state.overlay {
+                        Some(overlay) if node.attr.upper => Some(overlay.path(&state.path(inode))),
+                        _ => None,
+                    };
+                    (node.clone(), upper_path)
+                }
+                None => {
+                    reply.error(libc::ENOENT);
+                    return;
+                }
             }
         };
 
-        let blob = match self.cas_client.read_blob(&inode.attr.hash, inode.attr.size) {
-            Ok(blob) => blob,
-            Err(_) => {
-                reply.error(libc::ENOSYS);
+        if let Some(path) = upper_path {
+            let mut buff = vec![0; size as usize];
+            match fs::File::open(path).and_then(|f| f.read_at(&mut buff, offset as u64)) {
+                Ok(n) => reply.data(&buff[..n]),
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+            }
+            return;
+        }
+
+        let offset = offset as u64;
+        let data =
+            match self
+                .cas_client
+                .read_range(&inode.attr.hash, inode.attr.size, offset, size as u64)
+            {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!(hash = %inode.attr.hash, error = %e, "failed to read");
+                    reply.error(cas_errno(&e));
+                    return;
+                }
+            };
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&inode.attr.hash, inode.attr.size, offset);
+        }
+
+        let end = offset + data.len() as u64;
+        let sequential = self.read_ends.insert(inode.inode, end) == Some(offset);
+        if sequential {
+            self.prefetcher
+                .readahead(&inode.attr.hash, inode.attr.size, end);
+        }
+        reply.data(&data);
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _span = debug_span!("write", ino, fh, offset, size = data.len()).entered();
+        let _timer = op_timer("write");
+        let state = self.state.lock().unwrap();
+        let path = match (&state.overlay, state.inodes.get(&ino)) {
+            (Some(overlay), Some(inode)) if inode.attr.upper => overlay.path(&state.path(ino)),
+            (None, _) => {
+                reply.error(libc::EROFS);
+                return;
+            }
+            // the file is copied up when it is opened for write
+            _ => {
+                reply.error(libc::EBADF);
                 return;
             }
         };
 
-        let end: usize = cmp::min(inode.attr.size as usize, offset as usize + size as usize);
-        reply.data(&blob[offset as usize..end]);
+        let res = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.write_all_at(data, offset as u64));
+        match res {
+            Ok(_) => reply.written(data.len() as u32),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let _span = debug_span!("create", parent, ?name).entered();
+        let _timer = op_timer("create");
+        let mut state = self.state.lock().unwrap();
+        match state.create(
+            &self.cas_client,
+            parent,
+            name,
+            FileKind::File,
+            mode & !umask,
+        ) {
+            Ok(inode) => reply.created(&Duration::new(60, 0), &inode.into(), 0, 0, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let _span = debug_span!("mkdir", parent, ?name).entered();
+        let _timer = op_timer("mkdir");
+        let mut state = self.state.lock().unwrap();
+        match state.create(
+            &self.cas_client,
+            parent,
+            name,
+            FileKind::Directory,
+            mode & !umask,
+        ) {
+            Ok(inode) => reply.entry(&Duration::new(60, 0), &inode.into(), 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = debug_span!("unlink", parent, ?name).entered();
+        let _timer = op_timer("unlink");
+        let mut state = self.state.lock().unwrap();
+        match state.remove(&self.cas_client, parent, name, false) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = debug_span!("rmdir", parent, ?name).entered();
+        let _timer = op_timer("rmdir");
+        let mut state = self.state.lock().unwrap();
+        match state.remove(&self.cas_client, parent, name, true) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _span = debug_span!("rename", parent, ?name, newparent, ?newname).entered();
+        let _timer = op_timer("rename");
+        // RENAME_EXCHANGE and RENAME_NOREPLACE are not supported
+        if flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.rename(&self.cas_client, parent, name, newparent, newname) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
     }
 
     fn getxattr(
@@ -368,12 +1603,41 @@ impl Filesystem for Cfs {
         size: u32,
         reply: ReplyXattr,
     ) {
-        println!("getxattr (inode = {}, size = {})", inode, size);
+        let _span = debug_span!("getxattr", ino = inode, size).entered();
+        let _timer = op_timer("getxattr");
         reply.error(ENOSYS);
     }
 }
 
-pub fn run(mountpoint: &str, hash: &str, size: i64) -> Result<()> {
+/// Options of the mount
+#[derive(Debug, Default)]
+pub struct Options {
+    /// the unix socket to serve the control API
+    pub control_socket: Option<String>,
+
+    /// the address to serve the Prometheus metrics, eg. 0.0.0.0:9100
+    pub metrics_addr: Option<String>,
+
+    /// let the kernel unmount when the daemon dies, needs
+    /// `user_allow_other` in /etc/fuse.conf. The daemon unmounts on
+    /// SIGINT and SIGTERM either way
+    pub auto_unmount: bool,
+
+    /// the local scratch directory of the writable upper layer
+    pub upper_dir: Option<String>,
+
+    pub prefetch: PrefetchPolicy,
+
+    pub client: ClientOptions,
+
+    /// write the profile of the blobs being read to this file on unmount
+    pub record_profile: Option<String>,
+
+    /// prefetch the blobs of a recorded profile on mount
+    pub replay_profile: Option<String>,
+}
+
+pub fn run(mountpoint: &str, hash: &str, size: i64, options: Options) -> Result<()> {
     if !Path::new(mountpoint).is_dir() {
         let res = fs::create_dir(mountpoint);
         if res.is_err() {
@@ -382,8 +1646,291 @@ pub fn run(mountpoint: &str, hash: &str, size: i64) -> Result<()> {
         }
     }
 
-    let fs = Cfs::new(hash, size)?;
-    // TODO: why need to edit /etc/fuse.conf to enable user_allow_others to allow autoumount?
-    let mountoptions = vec![MountOption::AutoUnmount];
-    fuser::mount2(fs, &mountpoint, &mountoptions).map_err(|e| e.into())
+    let overlay = match &options.upper_dir {
+        Some(dir) => Some(Overlay::new(dir)?),
+        None => None,
+    };
+    let mut mountoptions = vec![];
+    if options.auto_unmount {
+        mountoptions.push(MountOption::AutoUnmount);
+    }
+    if overlay.is_none() {
+        mountoptions.push(MountOption::RO);
+    }
+
+    let cas_client = cas::blocking::CacheClient::with_options(options.client)?;
+    if options.prefetch.preload_tree {
+        let start = SystemTime::now();
+        let count = cas_client.preload_tree(hash, size, options.prefetch.preload_max_dirs)?;
+        info!(
+            "preloaded {} directories in {:?}",
+            count,
+            start.elapsed().unwrap_or_default()
+        );
+    }
+    let state = Arc::new(Mutex::new(MountState::new(hash, size, overlay)));
+
+    if let Some(path) = &options.replay_profile {
+        profile::replay(
+            Profile::load(path)?,
+            cas_client.clone(),
+            options.prefetch.concurrency,
+            options.prefetch.memory_budget,
+        );
+    }
+    let recorder = options
+        .record_profile
+        .as_ref()
+        .map(|_| Arc::new(Recorder::default()));
+
+    let prefetcher = Prefetcher::new(options.prefetch.clone(), cas_client.clone());
+    let fs = Cfs::new(
+        cas_client.clone(),
+        state.clone(),
+        prefetcher,
+        recorder.clone(),
+    );
+    let mut session = Session::new(fs, Path::new(mountpoint), &mountoptions)?;
+
+    let handle = MountHandle {
+        mountpoint: mountpoint.to_string(),
+        cas_client,
+        state,
+        notifier: session.notifier(),
+    };
+    signals::spawn(handle.clone())?;
+    if let Some(socket) = &options.control_socket {
+        control::serve(socket, handle.clone())?;
+    }
+    if let Some(addr) = &options.metrics_addr {
+        metrics::serve(addr, handle)?;
+    }
+
+    let res = session.run().map_err(|e| e.into());
+
+    if let (Some(path), Some(recorder)) = (&options.record_profile, recorder) {
+        let profile = recorder.profile(format!("{}/{}", hash, size));
+        info!(
+            "writing profile of {} blobs to {}",
+            profile.accesses.len(),
+            path
+        );
+        if let Err(e) = profile.save(path) {
+            error!("failed to write profile {}: {}", path, e);
+        }
+    }
+
+    if let Some(socket) = &options.control_socket {
+        let _ = fs::remove_file(socket);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
+        DirectoryNode, FileNode, NodeProperties, SymlinkNode,
+    };
+    use cfs::cas::fake::FakeCas;
+    use cfs::test_util::TempDir;
+
+    #[test]
+    fn test_cas_errno() {
+        let status = |code| cas::Error::Status {
+            code,
+            message: String::new(),
+            digest: None,
+        };
+        assert_eq!(cas_errno(&status(Code::NotFound)), libc::ENOENT);
+        assert_eq!(cas_errno(&status(Code::Unauthenticated)), libc::EACCES);
+        assert_eq!(cas_errno(&status(Code::PermissionDenied)), libc::EACCES);
+        assert_eq!(cas_errno(&status(Code::ResourceExhausted)), libc::EAGAIN);
+        assert_eq!(cas_errno(&status(Code::Internal)), libc::EIO);
+        let corrupted = cas::Error::Corrupted {
+            digest: Digest::default(),
+            message: String::new(),
+        };
+        assert_eq!(errno(&corrupted.into()), libc::EIO);
+    }
+
+    #[test]
+    fn test_list_and_read_from_cas() {
+        let fake = FakeCas::new();
+        let hello = fake.insert(b"hello world".to_vec());
+        let sub = fake.insert_dir(&BazelDirectory::default());
+        let root = fake.insert_dir(&BazelDirectory {
+            files: vec![FileNode {
+                name: String::from("hello.txt"),
+                digest: Some(hello),
+                is_executable: false,
+                node_properties: None,
+            }],
+            directories: vec![DirectoryNode {
+                name: String::from("sub"),
+                digest: Some(sub),
+            }],
+            symlinks: vec![SymlinkNode {
+                name: String::from("link"),
+                target: String::from("hello.txt"),
+                node_properties: None,
+            }],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+
+        let cas_client = cas::blocking::CacheClient::new().unwrap();
+        let mut state = MountState::new(&root.hash, root.size_bytes, None);
+        let entries = state.list(&cas_client, 1).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.to_str().unwrap()).collect();
+        assert_eq!(names, vec![".", "..", "hello.txt", "link", "sub"]);
+
+        let ino = state.entry(1, OsStr::new("hello.txt")).unwrap();
+        let attr = state.attr(ino).unwrap().attr;
+        assert_eq!(attr.kind, FileKind::File);
+        let data = cas_client.read_range(&attr.hash, attr.size, 6, 5).unwrap();
+        assert_eq!(data, b"world");
+
+        let ino = state.entry(1, OsStr::new("link")).unwrap();
+        assert_eq!(state.attr(ino).unwrap().attr.kind, FileKind::Symlink);
+        assert_eq!(
+            state.link_target(&cas_client, ino).unwrap(),
+            OsString::from("hello.txt")
+        );
+    }
+
+    #[test]
+    fn test_copy_up_keeps_dir_mode() {
+        let fake = FakeCas::new();
+        let sub = fake.insert_dir(&BazelDirectory {
+            node_properties: Some(NodeProperties {
+                unix_mode: Some(0o700),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let root = fake.insert_dir(&BazelDirectory {
+            directories: vec![DirectoryNode {
+                name: String::from("sub"),
+                digest: Some(sub.clone()),
+            }],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+
+        let upper = TempDir::new("fuse-copy-up").unwrap();
+        let cas_client = cas::blocking::CacheClient::new().unwrap();
+        let overlay = Overlay::new(upper.path()).unwrap();
+        let mut state = MountState::new(&root.hash, root.size_bytes, Some(overlay));
+        let sub_ino = state.list(&cas_client, 1).unwrap()[2].ino;
+        state
+            .create(
+                &cas_client,
+                sub_ino,
+                OsStr::new("a.txt"),
+                FileKind::File,
+                0o644,
+            )
+            .unwrap();
+
+        let md = fs::metadata(upper.path().join("sub")).unwrap();
+        assert_eq!(md.permissions().mode() & 0o7777, 0o700);
+    }
+
+    #[test]
+    fn test_copy_up_file() {
+        let fake = FakeCas::new();
+        let data = "hello world ".repeat(1000).into_bytes();
+        let hello = fake.insert(data.clone());
+        let root = fake.insert_dir(&BazelDirectory {
+            files: vec![FileNode {
+                name: String::from("hello.txt"),
+                digest: Some(hello.clone()),
+                is_executable: false,
+                node_properties: None,
+            }],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+
+        let upper = TempDir::new("fuse-copy-up-file").unwrap();
+        let cas_client = cas::blocking::CacheClient::new().unwrap();
+        let overlay = Overlay::new(upper.path()).unwrap();
+        let mut state = MountState::new(&root.hash, root.size_bytes, Some(overlay));
+        let ino = state.list(&cas_client, 1).unwrap()[2].ino;
+        state.copy_up(&cas_client, ino).unwrap();
+
+        assert_eq!(fs::read(upper.path().join("hello.txt")).unwrap(), data);
+        // streamed into the upper file without going through the cache
+        assert!(!cas_client.contains(&hello.hash));
+    }
+
+    #[test]
+    fn test_remap_changed_dirs() {
+        let fake = FakeCas::new();
+        let hello = fake.insert(b"hello".to_vec());
+        let world = fake.insert(b"world".to_vec());
+        let dir = |files: Vec<(&str, &Digest)>, dirs: Vec<(&str, &Digest)>| BazelDirectory {
+            files: files
+                .into_iter()
+                .map(|(name, digest)| FileNode {
+                    name: String::from(name),
+                    digest: Some(digest.clone()),
+                    is_executable: false,
+                    node_properties: None,
+                })
+                .collect(),
+            directories: dirs
+                .into_iter()
+                .map(|(name, digest)| DirectoryNode {
+                    name: String::from(name),
+                    digest: Some(digest.clone()),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let old_deep = fake.insert_dir(&dir(vec![("a.txt", &hello)], vec![]));
+        let old_sub = fake.insert_dir(&dir(vec![], vec![("deep", &old_deep)]));
+        let old_root = fake.insert_dir(&dir(vec![], vec![("sub", &old_sub)]));
+        let new_deep = fake.insert_dir(&dir(vec![("a.txt", &world)], vec![]));
+        let new_sub = fake.insert_dir(&dir(vec![], vec![("deep", &new_deep)]));
+        let new_root = fake.insert_dir(&dir(vec![], vec![("sub", &new_sub)]));
+        let _server = fake.start().unwrap();
+
+        let cas_client = cas::blocking::CacheClient::new().unwrap();
+        let mut state = MountState::new(&old_root.hash, old_root.size_bytes, None);
+        let sub = state.list(&cas_client, 1).unwrap()[2].ino;
+        let deep = state.list(&cas_client, sub).unwrap()[2].ino;
+        state.list(&cas_client, deep).unwrap();
+
+        // one level is found per round
+        let root = cas_client
+            .read_dir(&new_root.hash, new_root.size_bytes)
+            .unwrap();
+        let mut dirs = HashMap::new();
+        let mut rounds = 0;
+        loop {
+            let mut missing = vec![];
+            state.missing_dirs(1, &root, &dirs, &mut missing);
+            if missing.is_empty() {
+                break;
+            }
+            rounds += 1;
+            for digest in missing {
+                let dir = cas_client
+                    .read_dir(&digest.hash, digest.size_bytes)
+                    .unwrap();
+                dirs.insert(digest.hash, dir);
+            }
+        }
+        assert_eq!(rounds, 2);
+
+        let mut invalidations = vec![];
+        state.remap(1, &root, &dirs, &mut invalidations);
+        assert_eq!(state.entry(1, OsStr::new("sub")), Some(sub));
+        assert_eq!(state.entry(sub, OsStr::new("deep")), Some(deep));
+        assert_eq!(state.inodes[&deep].attr.hash, new_deep.hash);
+        // the changed file is looked up again
+        assert_eq!(state.entry(deep, OsStr::new("a.txt")), None);
+    }
 }
@@ -1,7 +1,17 @@
 use anyhow::Result;
+use cfs::cas;
+use cfs::cas::blocking::ClientOptions;
+use cfs::cas::compression::{self, CompressionOptions};
+use cfs::logging;
 use clap::{crate_version, Arg, Command};
 
+mod control;
 mod fuse;
+mod metrics;
+mod overlay;
+mod prefetch;
+mod profile;
+mod signals;
 
 fn main() -> Result<()> {
     let app = Command::new("cfs daemon")
@@ -11,7 +21,113 @@ fn main() -> Result<()> {
         .arg(
             Arg::new("auto_unmount")
                 .long("auto_unmount")
-                .help("Automatically unmount on process exit"),
+                .help("Let the kernel unmount when the daemon dies, needs user_allow_other in /etc/fuse.conf"),
+        )
+        .arg(
+            Arg::new("control_socket")
+                .long("control_socket")
+                .takes_value(true)
+                .help("The unix socket to serve the control API, eg. /tmp/cfsd.sock"),
+        )
+        .arg(
+            Arg::new("metrics_addr")
+                .long("metrics_addr")
+                .takes_value(true)
+                .help("The address to serve the Prometheus metrics at /metrics, eg. 0.0.0.0:9100"),
+        )
+        .arg(
+            Arg::new("upper_dir")
+                .long("upper_dir")
+                .takes_value(true)
+                .help("The local scratch directory of the writable layer, the mount is read-only without it"),
+        )
+        .arg(
+            Arg::new("readahead")
+                .long("readahead")
+                .takes_value(true)
+                .help("Number of 4MiB chunks to read ahead once a file is read sequentially, 0 disables it"),
+        )
+        .arg(
+            Arg::new("prefetch_listing_max")
+                .long("prefetch_listing_max")
+                .takes_value(true)
+                .help("Fetch the files up to this many bytes when their directory is listed, 0 disables it"),
+        )
+        .arg(
+            Arg::new("prefetch_open_max")
+                .long("prefetch_open_max")
+                .takes_value(true)
+                .help("Fetch the whole file up to this many bytes when it is opened, 0 disables it"),
+        )
+        .arg(
+            Arg::new("prefetch_concurrency")
+                .long("prefetch_concurrency")
+                .takes_value(true)
+                .help("Max number of concurrent prefetches"),
+        )
+        .arg(
+            Arg::new("prefetch_memory")
+                .long("prefetch_memory")
+                .takes_value(true)
+                .help("Stop prefetching once the cache holds this many bytes, the cache itself is not bounded"),
+        )
+        .arg(
+            Arg::new("preload_tree")
+                .long("preload_tree")
+                .help("Fetch the directories of the tree with GetTree on mount and when a subtree is reached"),
+        )
+        .arg(
+            Arg::new("preload_max_dirs")
+                .long("preload_max_dirs")
+                .takes_value(true)
+                .help("Max number of directories fetched by a single tree preload"),
+        )
+        .arg(
+            Arg::new("record_profile")
+                .long("record_profile")
+                .takes_value(true)
+                .help("Record the blobs being read and write the profile to the file on unmount"),
+        )
+        .arg(
+            Arg::new("replay_profile")
+                .long("replay_profile")
+                .takes_value(true)
+                .help("Prefetch the blobs of a recorded profile on mount"),
+        )
+        .arg(
+            Arg::new("compression")
+                .long("compression")
+                .takes_value(true)
+                .possible_values(["auto", "zstd", "none"])
+                .default_value("auto")
+                .help("Compression of the blobs being transferred, auto uses zstd when the server supports it"),
+        )
+        .arg(
+            Arg::new("compression_level")
+                .long("compression_level")
+                .takes_value(true)
+                .help("zstd compression level of the blobs being uploaded"),
+        )
+        .arg(
+            Arg::new("digest_function")
+                .long("digest_function")
+                .takes_value(true)
+                .possible_values(["sha256", "blake3"])
+                .help("The hash function of the digests, picked from the server capabilities by default"),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log_format")
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .default_value("text")
+                .help("Format of the logs, the level is set with RUST_LOG"),
+        )
+        .arg(
+            Arg::new("log_file")
+                .long("log_file")
+                .takes_value(true)
+                .help("Append the logs to the file instead of stderr"),
         )
         .arg(
             Arg::new("DIGEST")
@@ -21,17 +137,59 @@ fn main() -> Result<()> {
         )
         .get_matches();
 
+    logging::init(app.value_of_t("log_format")?, app.value_of("log_file"))?;
+
     let digest = app
         .value_of("DIGEST")
         .ok_or(anyhow::Error::msg("fail to parse DIGEST"))?;
-    let tokens: Vec<_> = digest.split("/").collect();
-    if tokens.len() != 2 {
-        return Err(anyhow::Error::msg("malformed digest"));
+    let digest = cas::parse_digest(digest)?;
+
+    let mut prefetch = prefetch::PrefetchPolicy::default();
+    if app.is_present("readahead") {
+        prefetch.readahead_chunks = app.value_of_t("readahead")?;
+    }
+    if app.is_present("prefetch_listing_max") {
+        prefetch.listing_max_size = app.value_of_t("prefetch_listing_max")?;
+    }
+    if app.is_present("prefetch_open_max") {
+        prefetch.open_max_size = app.value_of_t("prefetch_open_max")?;
+    }
+    if app.is_present("prefetch_concurrency") {
+        prefetch.concurrency = app.value_of_t("prefetch_concurrency")?;
+    }
+    if app.is_present("prefetch_memory") {
+        prefetch.memory_budget = app.value_of_t("prefetch_memory")?;
+    }
+    prefetch.preload_tree = app.is_present("preload_tree");
+    if app.is_present("preload_max_dirs") {
+        prefetch.preload_max_dirs = app.value_of_t("preload_max_dirs")?;
     }
 
-    let hash = tokens[0];
-    let size = tokens[1].parse::<i64>().unwrap();
+    let mut compression = CompressionOptions {
+        compression: app.value_of_t("compression")?,
+        level: compression::DEFAULT_LEVEL,
+    };
+    if app.is_present("compression_level") {
+        compression.level = app.value_of_t("compression_level")?;
+    }
 
     let mountpoint = app.value_of("MOUNT_POINT").unwrap();
-    fuse::run(mountpoint, hash, size).map_err(|e| e.into())
+    let options = fuse::Options {
+        control_socket: app.value_of("control_socket").map(|s| s.to_string()),
+        metrics_addr: app.value_of("metrics_addr").map(|s| s.to_string()),
+        auto_unmount: app.is_present("auto_unmount"),
+        upper_dir: app.value_of("upper_dir").map(|s| s.to_string()),
+        prefetch,
+        client: ClientOptions {
+            compression,
+            digest_function: match app.value_of("digest_function") {
+                Some(f) => Some(f.parse()?),
+                None => None,
+            },
+            ..Default::default()
+        },
+        record_profile: app.value_of("record_profile").map(|s| s.to_string()),
+        replay_profile: app.value_of("replay_profile").map(|s| s.to_string()),
+    };
+    fuse::run(mountpoint, &digest.hash, digest.size_bytes, options)
 }
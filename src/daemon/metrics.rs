@@ -0,0 +1,73 @@
+use super::fuse::MountHandle;
+use anyhow::Result;
+use cfs::metrics;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Start the HTTP listener at `addr` serving the metrics in the Prometheus
+/// text format at `/metrics`
+///
+/// The scrapes are rare and cheap, so the connections are served one by
+/// one on a single thread.
+pub fn serve(addr: &str, handle: MountHandle) -> Result<()> {
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        anyhow::Error::msg(format!("failed to bind metrics address {}: {}", addr, e))
+    })?;
+    info!(
+        "serving metrics at http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve_connection(stream, &handle) {
+                        warn!("metrics connection failed {}", e);
+                    }
+                }
+                Err(e) => warn!("failed to accept metrics connection {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn serve_connection(mut stream: TcpStream, handle: &MountHandle) -> Result<()> {
+    // a stuck client should not block the scrapes after it
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // only the request line matters, the headers are read till the blank line
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["GET", "/metrics", ..] => ("200 OK", render(handle)?),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// the gauges of the cache and the inode table are sampled on scrape
+fn render(handle: &MountHandle) -> Result<String> {
+    let status = handle.status();
+    metrics::CACHE_BYTES.set(status.cache.bytes as i64);
+    metrics::CACHE_BLOBS.set(status.cache.blobs as i64);
+    metrics::INODES.set(status.inodes as i64);
+    metrics::encode()
+}
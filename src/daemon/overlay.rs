@@ -0,0 +1,367 @@
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
+    Digest, Directory, DirectoryNode, FileNode, NodeProperties, SymlinkNode,
+};
+use cfs::cas::blocking::{CacheClient, Client};
+use cfs::hash::DigestFunction;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// prefix of the whiteout files that hide the entries of the lower layer
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// marker file of a directory that hides all the entries of the lower layer
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Overlay is the copy-on-write upper layer of the mount backed by a local
+/// scratch directory. The paths are relative to the mount root.
+///
+/// Entries removed from the lower layer are recorded as whiteout files
+/// `.wh.{name}` next to where the entries would be, and a directory
+/// recreated over a removed one is marked opaque, the same way overlayfs
+/// and aufs lay out their upper layers.
+#[derive(Clone)]
+pub struct Overlay {
+    root: PathBuf,
+}
+
+impl Overlay {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Overlay> {
+        fs::create_dir_all(root.as_ref())?;
+        Ok(Overlay {
+            root: root.as_ref().to_path_buf(),
+        })
+    }
+
+    /// names used by the overlay itself cannot be created in the mount
+    pub fn is_reserved(name: &OsStr) -> bool {
+        name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+    }
+
+    /// the path of the entry in the upper layer
+    pub fn path(&self, rel: &Path) -> PathBuf {
+        self.root.join(rel)
+    }
+
+    fn whiteout_path(&self, rel: &Path) -> PathBuf {
+        let mut name = OsString::from(WHITEOUT_PREFIX);
+        name.push(rel.file_name().unwrap_or_default());
+        self.path(rel).with_file_name(name)
+    }
+
+    /// metadata of the entry in the upper layer, None if the entry is not in the upper layer
+    pub fn metadata(&self, rel: &Path) -> Option<fs::Metadata> {
+        fs::symlink_metadata(self.path(rel)).ok()
+    }
+
+    pub fn is_whiteout(&self, rel: &Path) -> bool {
+        fs::symlink_metadata(self.whiteout_path(rel)).is_ok()
+    }
+
+    /// hide the entry of the lower layer
+    pub fn add_whiteout(&self, rel: &Path) -> Result<()> {
+        File::create(self.whiteout_path(rel))?;
+        Ok(())
+    }
+
+    /// returns whether the entry was hidden
+    pub fn remove_whiteout(&self, rel: &Path) -> Result<bool> {
+        match fs::remove_file(self.whiteout_path(rel)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// whether the directory hides all the entries of the lower layer
+    pub fn is_opaque(&self, rel: &Path) -> bool {
+        fs::symlink_metadata(self.path(rel).join(OPAQUE_MARKER)).is_ok()
+    }
+
+    pub fn set_opaque(&self, rel: &Path) -> Result<()> {
+        File::create(self.path(rel).join(OPAQUE_MARKER))?;
+        Ok(())
+    }
+
+    /// list the directory in the upper layer, returns the visible entries
+    /// and the names being hidden from the lower layer
+    pub fn read_dir(&self, rel: &Path) -> Result<(Vec<(OsString, fs::Metadata)>, Vec<OsString>)> {
+        let mut entries = vec![];
+        let mut whiteouts = vec![];
+        let path = self.path(rel);
+        if !path.is_dir() {
+            return Ok((entries, whiteouts));
+        }
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let bytes = name.as_bytes();
+            if bytes == OPAQUE_MARKER.as_bytes() {
+                continue;
+            }
+            if bytes.starts_with(WHITEOUT_PREFIX.as_bytes()) {
+                let hidden = OsStr::from_bytes(&bytes[WHITEOUT_PREFIX.len()..]);
+                whiteouts.push(hidden.to_os_string());
+                continue;
+            }
+            entries.push((name, entry.metadata()?));
+        }
+        Ok((entries, whiteouts))
+    }
+}
+
+/// hash the merged view of the lower tree at `{hash}/{size}` and the upper
+/// layer, upload the new blobs and returns the digest of the new root
+pub fn commit(
+    overlay: &Overlay,
+    cas_client: &CacheClient,
+    hash: &str,
+    size: i64,
+) -> Result<Digest> {
+    let mut commit = Commit {
+        overlay,
+        cas_client,
+        uploader: Client::with_options(cas_client.options())?,
+        digest_function: cas_client.digest_function(),
+    };
+    let lower = cas_client.get_dir(hash, size)?;
+    let root = commit.merge_directory(Path::new(""), Some(lower))?;
+    commit.create_directory_digest(root)
+}
+
+struct Commit<'a> {
+    overlay: &'a Overlay,
+
+    /// reads the directories of the lower tree
+    cas_client: &'a CacheClient,
+
+    /// uploads the new files and directories
+    uploader: Client,
+
+    /// the hash function of the lower tree
+    digest_function: DigestFunction,
+}
+
+impl<'a> Commit<'a> {
+    fn merge_directory(&mut self, rel: &Path, lower: Option<Directory>) -> Result<Directory> {
+        let lower = if self.overlay.is_opaque(rel) {
+            None
+        } else {
+            lower
+        };
+
+        let mut files = BTreeMap::new();
+        let mut directories = BTreeMap::new();
+        let mut symlinks = BTreeMap::new();
+        let mut lower_dirs = HashMap::new();
+        let mut node_properties = None;
+        let has_lower = lower.is_some();
+        if let Some(lower) = lower {
+            node_properties = lower.node_properties;
+            for f in lower.files {
+                files.insert(f.name.clone(), f);
+            }
+            for d in lower.directories {
+                lower_dirs.insert(d.name.clone(), d.digest.clone());
+                directories.insert(d.name.clone(), d);
+            }
+            for s in lower.symlinks {
+                symlinks.insert(s.name.clone(), s);
+            }
+        }
+        // the mode of the upper directory wins, eg. after a chmod through the
+        // mount. The upper root is the scratch directory itself, whose mode is
+        // not the one of the tree
+        if let Some(md) = self.overlay.metadata(rel) {
+            if !has_lower || !rel.as_os_str().is_empty() {
+                node_properties = Some(NodeProperties {
+                    unix_mode: Some(md.permissions().mode()),
+                    ..node_properties.unwrap_or_default()
+                });
+            }
+        }
+
+        let (upper, whiteouts) = self.overlay.read_dir(rel)?;
+        for name in whiteouts {
+            let name = name.to_string_lossy();
+            files.remove(&*name);
+            directories.remove(&*name);
+            symlinks.remove(&*name);
+        }
+
+        for (name, md) in upper {
+            let name = name
+                .to_str()
+                .map(|n| n.to_string())
+                .ok_or(anyhow::Error::msg(format!("invalid file name {:?}", name)))?;
+            // entries in the upper layer take over the ones in the lower layer
+            files.remove(&name);
+            directories.remove(&name);
+            symlinks.remove(&name);
+
+            let rel = rel.join(&name);
+            let file_type = md.file_type();
+            if file_type.is_dir() {
+                let lower_dir = match lower_dirs.get(&name) {
+                    Some(Some(d)) => Some(self.cas_client.get_dir(&d.hash, d.size_bytes)?),
+                    _ => None,
+                };
+                let dir = self.merge_directory(&rel, lower_dir)?;
+                let digest = self.create_directory_digest(dir)?;
+                directories.insert(
+                    name.clone(),
+                    DirectoryNode {
+                        name,
+                        digest: Some(digest),
+                    },
+                );
+            } else if file_type.is_file() {
+                let path = self.overlay.path(&rel);
+                let (hash, size) = self.digest_function.hash_file(&path)?;
+                let digest = Digest {
+                    hash,
+                    size_bytes: size as i64,
+                };
+                self.uploader.write_file(&digest, &path)?;
+
+                let mode = md.permissions().mode();
+                files.insert(
+                    name.clone(),
+                    FileNode {
+                        name,
+                        digest: Some(digest),
+                        is_executable: mode & 0o111 != 0,
+                        node_properties: Some(NodeProperties {
+                            properties: vec![],
+                            mtime: None,
+                            unix_mode: Some(mode),
+                        }),
+                    },
+                );
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(self.overlay.path(&rel))?;
+                symlinks.insert(
+                    name.clone(),
+                    SymlinkNode {
+                        name,
+                        target: String::from(target.to_string_lossy()),
+                        node_properties: None,
+                    },
+                );
+            } else {
+                warn!("unknow path type: {:?}", rel);
+            }
+        }
+
+        // BTreeMap keeps the entries sorted by name as the canonical form requires
+        Ok(Directory {
+            files: files.into_values().collect(),
+            directories: directories.into_values().collect(),
+            symlinks: symlinks.into_values().collect(),
+            node_properties,
+        })
+    }
+
+    fn create_directory_digest(&mut self, dir: Directory) -> Result<Digest> {
+        let mut buff = vec![];
+        dir.encode(&mut buff)?;
+        let digest = Digest {
+            hash: self.digest_function.hash(&buff),
+            size_bytes: buff.len() as i64,
+        };
+        self.uploader.write_blob(&digest, &buff)?;
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfs::cas::fake::FakeCas;
+    use cfs::test_util::TempDir;
+
+    /// the overlay in a scratch directory, removed when the guard is dropped
+    fn temp_overlay(name: &str) -> (TempDir, Overlay) {
+        let dir = TempDir::new(&format!("overlay-{}", name)).unwrap();
+        let overlay = Overlay::new(dir.path()).unwrap();
+        (dir, overlay)
+    }
+
+    #[test]
+    fn test_whiteout() {
+        let (_dir, overlay) = temp_overlay("whiteout");
+        let rel = Path::new("a.txt");
+        assert!(!overlay.is_whiteout(rel));
+
+        overlay.add_whiteout(rel).unwrap();
+        assert!(overlay.is_whiteout(rel));
+        let (entries, whiteouts) = overlay.read_dir(Path::new("")).unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(whiteouts, vec![OsString::from("a.txt")]);
+
+        assert!(overlay.remove_whiteout(rel).unwrap());
+        assert!(!overlay.remove_whiteout(rel).unwrap());
+        assert!(!overlay.is_whiteout(rel));
+    }
+
+    #[test]
+    fn test_opaque_directory() {
+        let (_dir, overlay) = temp_overlay("opaque");
+        let rel = Path::new("dir");
+        fs::create_dir(overlay.path(rel)).unwrap();
+        assert!(!overlay.is_opaque(rel));
+
+        overlay.set_opaque(rel).unwrap();
+        assert!(overlay.is_opaque(rel));
+        let (entries, whiteouts) = overlay.read_dir(rel).unwrap();
+        assert!(entries.is_empty());
+        assert!(whiteouts.is_empty());
+    }
+
+    #[test]
+    fn test_commit_upper_dir_mode() {
+        let fake = FakeCas::new();
+        let sub = fake.insert_dir(&Directory {
+            node_properties: Some(NodeProperties {
+                unix_mode: Some(0o755),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let root = fake.insert_dir(&Directory {
+            directories: vec![DirectoryNode {
+                name: String::from("sub"),
+                digest: Some(sub),
+            }],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+
+        let (_dir, overlay) = temp_overlay("commit-mode");
+        let path = overlay.path(Path::new("sub"));
+        fs::create_dir(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let cas_client = CacheClient::new().unwrap();
+        let digest = commit(&overlay, &cas_client, &root.hash, root.size_bytes).unwrap();
+        let root = Directory::decode(&*fake.get(&digest.hash).unwrap()).unwrap();
+        let sub = root.directories[0].digest.as_ref().unwrap();
+        let sub = Directory::decode(&*fake.get(&sub.hash).unwrap()).unwrap();
+        let mode = sub.node_properties.unwrap().unix_mode.unwrap();
+        assert_eq!(mode & 0o7777, 0o700);
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        assert!(Overlay::is_reserved(OsStr::new(".wh.a.txt")));
+        assert!(!Overlay::is_reserved(OsStr::new("a.txt")));
+    }
+}
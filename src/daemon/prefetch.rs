@@ -0,0 +1,243 @@
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
+use cfs::cas::blocking::{chunk_key, CacheClient, CHUNK_SIZE};
+use std::cmp;
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::warn;
+
+/// max number of fetches waiting in the queue, more requests are dropped
+/// since a prefetch that starts too late is not useful anyway
+const MAX_QUEUED_FETCHES: usize = 1024;
+
+/// Policies of fetching the content ahead of the reads
+#[derive(Debug, Clone)]
+pub struct PrefetchPolicy {
+    /// number of chunks to read ahead once a file is read sequentially, 0 disables read-ahead
+    pub readahead_chunks: u64,
+
+    /// fetch the files up to this size when their directory is listed, 0 disables it
+    pub listing_max_size: u64,
+
+    /// fetch the whole file up to this size when it is opened, 0 disables it
+    pub open_max_size: u64,
+
+    /// max number of concurrent fetches
+    pub concurrency: usize,
+
+    /// no more prefetch once the cache grows beyond this size. It only gates
+    /// new prefetches, nothing is evicted and the reads of the mount keep
+    /// growing the cache beyond it
+    pub memory_budget: u64,
+
+    /// fetch the directories of the whole tree with GetTree on mount, and
+    /// the subtrees of the directories reached but not being fetched yet
+    pub preload_tree: bool,
+
+    /// max number of directories fetched by a single tree preload
+    pub preload_max_dirs: usize,
+}
+
+impl Default for PrefetchPolicy {
+    fn default() -> Self {
+        PrefetchPolicy {
+            readahead_chunks: 2,
+            listing_max_size: 64 * 1024,
+            open_max_size: 16 * 1024 * 1024,
+            concurrency: 4,
+            memory_budget: 1024 * 1024 * 1024,
+            preload_tree: false,
+            preload_max_dirs: 10000,
+        }
+    }
+}
+
+enum Fetch {
+    Blob {
+        hash: String,
+        size: i64,
+    },
+    Chunk {
+        hash: String,
+        size: i64,
+        index: u64,
+    },
+    Tree {
+        hash: String,
+        size: i64,
+    },
+    /// small blobs read together with BatchReadBlobs
+    Batch {
+        digests: Vec<Digest>,
+    },
+}
+
+impl Fetch {
+    fn keys(&self) -> Vec<String> {
+        match self {
+            Fetch::Blob { hash, .. } => vec![hash.clone()],
+            Fetch::Chunk { hash, index, .. } => vec![chunk_key(hash, *index)],
+            Fetch::Tree { hash, .. } => vec![format!("tree:{}", hash)],
+            Fetch::Batch { digests } => digests.iter().map(|d| d.hash.clone()).collect(),
+        }
+    }
+}
+
+/// Prefetcher fetches blobs into the cache in the background
+///
+/// The fetches are served by a fixed number of worker threads, and are
+/// dropped when the cache is over the memory budget or when the same
+/// blob is already cached or queued. The cache is not bounded by the
+/// budget, the blobs already fetched stay cached.
+pub struct Prefetcher {
+    policy: PrefetchPolicy,
+
+    cas_client: CacheClient,
+
+    sender: Sender<Fetch>,
+
+    /// keys of the fetches being queued or in flight
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Prefetcher {
+    pub fn new(policy: PrefetchPolicy, cas_client: CacheClient) -> Prefetcher {
+        let (sender, receiver) = mpsc::channel::<Fetch>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+
+        for _ in 0..policy.concurrency.max(1) {
+            let receiver = receiver.clone();
+            let pending = pending.clone();
+            let cas_client = cas_client.clone();
+            let memory_budget = policy.memory_budget;
+            let max_dirs = policy.preload_max_dirs;
+            thread::spawn(move || loop {
+                let fetch = match receiver.lock().unwrap().recv() {
+                    Ok(fetch) => fetch,
+                    // the prefetcher is dropped
+                    Err(_) => break,
+                };
+
+                if cas_client.cached_bytes() < memory_budget {
+                    let res = match &fetch {
+                        Fetch::Blob { hash, size } => cas_client.read_blob(hash, *size).map(|_| ()),
+                        Fetch::Chunk { hash, size, index } => {
+                            cas_client.read_chunk(hash, *size, *index).map(|_| ())
+                        }
+                        Fetch::Tree { hash, size } => {
+                            cas_client.preload_tree(hash, *size, max_dirs).map(|_| ())
+                        }
+                        Fetch::Batch { digests } => cas_client.read_blobs(digests).map(|_| ()),
+                    };
+                    if let Err(e) = res {
+                        warn!(keys = ?fetch.keys(), error = %e, "failed to prefetch");
+                    }
+                }
+                let mut pending = pending.lock().unwrap();
+                for key in fetch.keys() {
+                    pending.remove(&key);
+                }
+            });
+        }
+
+        Prefetcher {
+            policy,
+            cas_client,
+            sender,
+            pending,
+        }
+    }
+
+    pub fn policy(&self) -> &PrefetchPolicy {
+        &self.policy
+    }
+
+    /// fetch the whole file, by chunks when it is larger than a chunk
+    pub fn file(&self, hash: &str, size: i64) {
+        if size as u64 <= CHUNK_SIZE {
+            self.submit(Fetch::Blob {
+                hash: hash.to_string(),
+                size,
+            });
+        } else {
+            self.chunks(hash, size, 0, (size as u64 + CHUNK_SIZE - 1) / CHUNK_SIZE);
+        }
+    }
+
+    /// fetch the small files together in batches
+    pub fn files(&self, digests: Vec<Digest>) {
+        if digests.is_empty() || self.cas_client.cached_bytes() >= self.policy.memory_budget {
+            return;
+        }
+        let digests: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            digests
+                .into_iter()
+                .filter(|d| {
+                    pending.len() < MAX_QUEUED_FETCHES
+                        && !self.cas_client.contains(&d.hash)
+                        && pending.insert(d.hash.clone())
+                })
+                .collect()
+        };
+        if !digests.is_empty() {
+            let _ = self.sender.send(Fetch::Batch { digests });
+        }
+    }
+
+    /// preload the tree under the directory unless it is already cached
+    pub fn tree(&self, hash: &str, size: i64) {
+        if !self.policy.preload_tree || self.cas_client.contains_dir(hash) {
+            return;
+        }
+        self.submit(Fetch::Tree {
+            hash: hash.to_string(),
+            size,
+        });
+    }
+
+    /// read ahead the chunks following the read ending at `end`
+    pub fn readahead(&self, hash: &str, size: i64, end: u64) {
+        if self.policy.readahead_chunks == 0 || size as u64 <= CHUNK_SIZE {
+            return;
+        }
+        let next = (end + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        self.chunks(hash, size, next, self.policy.readahead_chunks);
+    }
+
+    fn chunks(&self, hash: &str, size: i64, from: u64, count: u64) {
+        let last = (size as u64 + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        for index in from..cmp::min(from + count, last) {
+            if self.cas_client.contains_chunk(hash, index) {
+                continue;
+            }
+            self.submit(Fetch::Chunk {
+                hash: hash.to_string(),
+                size,
+                index,
+            });
+        }
+    }
+
+    fn submit(&self, fetch: Fetch) {
+        if self.cas_client.cached_bytes() >= self.policy.memory_budget {
+            return;
+        }
+        if let Fetch::Blob { hash, .. } = &fetch {
+            if self.cas_client.contains(hash) {
+                return;
+            }
+        }
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let key = fetch.keys().remove(0);
+            if pending.len() >= MAX_QUEUED_FETCHES || !pending.insert(key) {
+                return;
+            }
+        }
+        let _ = self.sender.send(fetch);
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
+use cfs::cas;
+use cfs::cas::blocking::{CacheClient, CHUNK_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tracing::info;
+
+/// Profile is the ordered list of the blobs a workload read from a mount
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Profile {
+    /// the root digest the profile was recorded on in the form of `{hash}/{size}`
+    pub root: String,
+
+    pub accesses: Vec<Access>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Access {
+    pub hash: String,
+    pub size: i64,
+
+    /// offset of the first read, 0 for directories
+    pub offset: u64,
+}
+
+impl Profile {
+    pub fn load(path: &str) -> Result<Profile> {
+        let f = File::open(path)
+            .map_err(|e| anyhow::Error::msg(format!("failed to open profile {}: {}", path, e)))?;
+        serde_json::from_reader(BufReader::new(f)).map_err(|e| e.into())
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let f = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(f), self).map_err(|e| e.into())
+    }
+}
+
+/// Recorder records the first read of each blob, or of each chunk of a
+/// large blob, in the order of the reads
+#[derive(Default)]
+pub struct Recorder {
+    state: Mutex<RecorderState>,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    seen: HashSet<(String, u64)>,
+    accesses: Vec<Access>,
+}
+
+impl Recorder {
+    pub fn record(&self, hash: &str, size: i64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.seen.insert((hash.to_string(), offset / CHUNK_SIZE)) {
+            state.accesses.push(Access {
+                hash: hash.to_string(),
+                size,
+                offset,
+            });
+        }
+    }
+
+    pub fn profile(&self, root: String) -> Profile {
+        Profile {
+            root,
+            accesses: self.state.lock().unwrap().accesses.clone(),
+        }
+    }
+}
+
+/// number of small blobs read by a single batch read of a replay
+const REPLAY_BATCH_COUNT: usize = 100;
+
+/// Fetch is a unit of work of a replay
+#[derive(Debug)]
+enum Fetch {
+    /// blobs up to [`CHUNK_SIZE`], read together with BatchReadBlobs
+    Batch(Vec<Digest>),
+
+    /// a chunk of a larger blob, read with ByteStream
+    Chunk(Access),
+}
+
+/// ReplayStats counts the blobs that could not be fetched
+#[derive(Debug, Default)]
+struct ReplayStats {
+    not_found: u64,
+    failed: u64,
+}
+
+impl ReplayStats {
+    fn add<T>(&mut self, res: cas::Result<T>) {
+        match res {
+            Ok(_) => {}
+            Err(e) if e.is_not_found() => self.not_found += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+}
+
+/// group the small blobs into batches. The batches and the chunks are in
+/// the order of the accesses that complete a batch or read a chunk
+fn plan(accesses: Vec<Access>) -> Vec<Fetch> {
+    let mut fetches = vec![];
+    let mut batch = vec![];
+    for access in accesses {
+        if access.size as u64 > CHUNK_SIZE {
+            fetches.push(Fetch::Chunk(access));
+            continue;
+        }
+        batch.push(Digest {
+            hash: access.hash,
+            size_bytes: access.size,
+        });
+        if batch.len() == REPLAY_BATCH_COUNT {
+            fetches.push(Fetch::Batch(std::mem::take(&mut batch)));
+        }
+    }
+    if !batch.is_empty() {
+        fetches.push(Fetch::Batch(batch));
+    }
+    fetches
+}
+
+/// fetch into the cache. When a batch fails, eg. on a blob missing from
+/// CAS, its blobs are read one by one to fetch the others
+fn fetch(cas_client: &CacheClient, fetch: Fetch, stats: &Mutex<ReplayStats>) {
+    match fetch {
+        Fetch::Batch(digests) => {
+            if cas_client.read_blobs(&digests).is_ok() {
+                return;
+            }
+            for d in digests {
+                let res = cas_client.read_blob(&d.hash, d.size_bytes);
+                stats.lock().unwrap().add(res);
+            }
+        }
+        Fetch::Chunk(access) => {
+            let res = cas_client.read_chunk(&access.hash, access.size, access.offset / CHUNK_SIZE);
+            stats.lock().unwrap().add(res);
+        }
+    }
+}
+
+/// fetch the blobs of the profile into the cache in the recorded order
+/// with `concurrency` threads until the cache holds `memory_budget` bytes.
+/// The small blobs are fetched in batches, the chunks of the large ones
+/// one by one.
+///
+/// Blobs missing from CAS are skipped, so a profile recorded on a related
+/// root still warms up the blobs the two trees share.
+pub fn replay(profile: Profile, cas_client: CacheClient, concurrency: usize, memory_budget: u64) {
+    let total = profile.accesses.len();
+    let fetches = Arc::new(Mutex::new(plan(profile.accesses).into_iter()));
+    let stats = Arc::new(Mutex::new(ReplayStats::default()));
+
+    thread::spawn(move || {
+        let start = Instant::now();
+        let workers: Vec<_> = (0..concurrency.max(1))
+            .map(|_| {
+                let fetches = fetches.clone();
+                let stats = stats.clone();
+                let cas_client = cas_client.clone();
+                thread::spawn(move || loop {
+                    let next = match fetches.lock().unwrap().next() {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    if cas_client.cached_bytes() >= memory_budget {
+                        break;
+                    }
+                    fetch(&cas_client, next, &stats);
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        let stats = stats.lock().unwrap();
+        info!(
+            "replayed profile of {} blobs in {:?}, {} not found, {} failed, {} bytes cached",
+            total,
+            start.elapsed(),
+            stats.not_found,
+            stats.failed,
+            cas_client.cached_bytes()
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfs::cas::fake::FakeCas;
+
+    #[test]
+    fn test_record_first_access_of_each_chunk() {
+        let recorder = Recorder::default();
+        recorder.record("a", 10, 0);
+        recorder.record("a", 10, 5);
+        recorder.record("b", 3 * CHUNK_SIZE as i64, CHUNK_SIZE);
+        recorder.record("b", 3 * CHUNK_SIZE as i64, CHUNK_SIZE + 10);
+        recorder.record("b", 3 * CHUNK_SIZE as i64, 0);
+
+        let profile = recorder.profile(String::from("r/1"));
+        let accesses: Vec<_> = profile
+            .accesses
+            .iter()
+            .map(|a| (a.hash.as_str(), a.offset))
+            .collect();
+        assert_eq!(accesses, vec![("a", 0), ("b", CHUNK_SIZE), ("b", 0)]);
+    }
+
+    #[test]
+    fn test_replay_batches_small_blobs() {
+        let fake = FakeCas::new();
+        let hello = fake.insert(b"hello".to_vec());
+        let world = fake.insert(b"world".to_vec());
+        let _server = fake.start().unwrap();
+
+        let access = |hash: &str, size| Access {
+            hash: hash.to_string(),
+            size,
+            offset: 0,
+        };
+        let large = 2 * CHUNK_SIZE as i64;
+        let fetches = plan(vec![
+            access(&hello.hash, hello.size_bytes),
+            access("large", large),
+            access("missing", 5),
+            access(&world.hash, world.size_bytes),
+        ]);
+        assert_eq!(fetches.len(), 2);
+        assert!(matches!(&fetches[0], Fetch::Chunk(a) if a.hash == "large"));
+
+        let cas_client = CacheClient::new().unwrap();
+        let stats = Mutex::new(ReplayStats::default());
+        for f in fetches {
+            fetch(&cas_client, f, &stats);
+        }
+        assert!(cas_client.contains(&hello.hash));
+        assert!(cas_client.contains(&world.hash));
+        assert!(fake.calls("BatchReadBlobs") > 0);
+        // the large blob and the missing one are not in CAS
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.not_found, 2);
+        assert_eq!(stats.failed, 0);
+    }
+}
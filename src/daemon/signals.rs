@@ -0,0 +1,43 @@
+use super::fuse::MountHandle;
+use anyhow::Result;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{error, info, warn};
+
+/// Handle the signals of the daemon on their own thread
+///
+/// SIGINT and SIGTERM unmount the filesystem, so that the FUSE session
+/// ends and the daemon exits the same way as on `fsx daemon unmount`. A
+/// busy mount is detached lazily on the next signal. SIGHUP connects to
+/// CAS again to pick up a rotated CA certificate or token, the options of
+/// the daemon are not reloaded.
+pub fn spawn(handle: MountHandle) -> Result<()> {
+    let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGHUP])?;
+
+    std::thread::spawn(move || {
+        let mut stopping = false;
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => match handle.reload() {
+                    Ok(()) => info!("reconnected to CAS"),
+                    Err(e) => error!("failed to reconnect to CAS {}", e),
+                },
+                _ if !stopping => {
+                    info!("received signal {}, unmounting", signal);
+                    stopping = true;
+                    if let Err(e) = handle.unmount() {
+                        warn!("{}, signal again to detach the busy mount", e);
+                    }
+                }
+                _ => {
+                    info!("received signal {}, detaching the mount", signal);
+                    if let Err(e) = handle.detach() {
+                        error!("{}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
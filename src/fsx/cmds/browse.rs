@@ -0,0 +1,420 @@
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
+use cfs::cas;
+use cfs::cas::blocking::CacheClient;
+use serde::{Serialize, Serializer};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Kind of an entry of a CAS directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Entry is a file, a directory or a symlink of a tree in CAS
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub kind: Kind,
+    /// the unix mode of the node properties, defaulted the same way as
+    /// `fsx download` when it is not recorded
+    pub mode: u32,
+    /// size of the file, or of the encoded directory
+    pub size: i64,
+    /// None for symlinks, serialized as `hash/size`
+    #[serde(
+        serialize_with = "serialize_digest",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub digest: Option<Digest>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub executable: bool,
+    /// the target of a symlink
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::File => write!(f, "file"),
+            Kind::Directory => write!(f, "directory"),
+            Kind::Symlink => write!(f, "symlink"),
+        }
+    }
+}
+
+fn serialize_digest<S: Serializer>(digest: &Option<Digest>, s: S) -> Result<S::Ok, S::Error> {
+    match digest {
+        Some(d) => s.serialize_str(&digest_string(d)),
+        None => s.serialize_none(),
+    }
+}
+
+fn digest_string(d: &Digest) -> String {
+    format!("{}/{}", d.hash, d.size_bytes)
+}
+
+/// Node is an entry along with the entries under it, see [`tree`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub entry: Entry,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Node>,
+}
+
+/// list the directory at `path` of the tree, or the entry itself when it
+/// is not a directory
+pub fn ls(digest: String, path: Option<String>, json: bool) -> Result<()> {
+    let root = cas::parse_digest(&digest)?;
+    let client = CacheClient::new()?;
+    let entry = resolve(&client, &root, path.as_deref().unwrap_or(""), false)?;
+    let entries = match entry.kind {
+        Kind::Directory => list(&client, &entry)?,
+        _ => vec![entry],
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    for e in &entries {
+        println!(
+            "{} {:>12} {:<70} {}",
+            mode_string(e),
+            e.size,
+            e.digest.as_ref().map_or(String::from("-"), digest_string),
+            display_name(e)
+        );
+    }
+    Ok(())
+}
+
+/// print the tree under the root directory, down to `depth` levels
+pub fn tree(digest: String, depth: Option<usize>, json: bool) -> Result<()> {
+    let root = cas::parse_digest(&digest)?;
+    let client = CacheClient::new()?;
+    let node = walk(&client, resolve(&client, &root, "", false)?, depth)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&node)?);
+        return Ok(());
+    }
+    println!("{}", digest);
+    print_children(&node, "");
+    Ok(())
+}
+
+/// print the entry at `path` of the tree
+pub fn stat(digest: String, path: String, json: bool) -> Result<()> {
+    let root = cas::parse_digest(&digest)?;
+    let client = CacheClient::new()?;
+    let entry = resolve(&client, &root, &path, false)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entry)?);
+        return Ok(());
+    }
+    println!("name:       {}", entry.name);
+    println!("kind:       {}", entry.kind);
+    println!("mode:       {:o} ({})", entry.mode, mode_string(&entry));
+    println!("size:       {}", entry.size);
+    if let Some(digest) = &entry.digest {
+        println!("digest:     {}", digest_string(digest));
+    }
+    if entry.kind == Kind::File {
+        println!("executable: {}", entry.executable);
+    }
+    if let Some(target) = &entry.target {
+        println!("target:     {}", target);
+    }
+    Ok(())
+}
+
+/// max number of symlinks followed by a single lookup, the same as ELOOP
+const MAX_SYMLINKS: usize = 40;
+
+/// find the entry at the slash separated `path` under the root directory,
+/// an empty path is the root itself. The symlinks in the middle of the
+/// path are followed, and the last one too with `follow`. Symlinks are
+/// resolved inside the tree, absolute ones and the ones leading out of
+/// the root are errors
+pub fn resolve(client: &CacheClient, root: &Digest, path: &str, follow: bool) -> Result<Entry> {
+    // the directories from the root to the entry being looked up
+    let mut parents = vec![dir_entry(client, ".", root)?];
+    let mut names: VecDeque<String> = components(path);
+    let mut links = 0;
+    while let Some(name) = names.pop_front() {
+        let dir = parents.last().unwrap();
+        if name == ".." {
+            if parents.len() == 1 {
+                return Err(anyhow::Error::msg(format!(
+                    "{} leads out of the tree",
+                    path
+                )));
+            }
+            parents.pop();
+            continue;
+        }
+        if dir.kind != Kind::Directory {
+            return Err(anyhow::Error::msg(format!(
+                "{} is not a directory",
+                dir.name
+            )));
+        }
+        let entry = list_nodes(client, dir)?
+            .into_iter()
+            .find(|e| e.name == name)
+            .ok_or_else(|| anyhow::Error::msg(format!("{} not found under {}", name, dir.name)))?;
+        let entry = with_dir_mode(client, entry)?;
+
+        if entry.kind == Kind::Symlink && (follow || !names.is_empty()) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(anyhow::Error::msg(format!("too many symlinks in {}", path)));
+            }
+            let target = entry.target.unwrap_or_default();
+            if target.starts_with('/') {
+                return Err(anyhow::Error::msg(format!(
+                    "{} links to the absolute path {}",
+                    entry.name, target
+                )));
+            }
+            for (i, n) in components(&target).into_iter().enumerate() {
+                names.insert(i, n);
+            }
+            continue;
+        }
+        parents.push(entry);
+    }
+    Ok(parents.pop().unwrap())
+}
+
+fn components(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|n| !n.is_empty() && *n != ".")
+        .map(|n| n.to_string())
+        .collect()
+}
+
+/// the entries of the directory sorted by name
+pub fn list(client: &CacheClient, dir: &Entry) -> Result<Vec<Entry>> {
+    list_nodes(client, dir)?
+        .into_iter()
+        .map(|e| with_dir_mode(client, e))
+        .collect()
+}
+
+/// same as `list` without fetching the child directories, so their mode
+/// is 0o755 until they go through `with_dir_mode`
+pub fn list_nodes(client: &CacheClient, dir: &Entry) -> Result<Vec<Entry>> {
+    let digest = dir.digest.clone().unwrap_or_default();
+    let dir = client.read_dir(&digest.hash, digest.size_bytes)?;
+    let mut entries = vec![];
+    for f in &dir.files {
+        let digest = f.digest.clone().unwrap_or_default();
+        entries.push(Entry {
+            name: f.name.clone(),
+            kind: Kind::File,
+            mode: match f.node_properties.as_ref().and_then(|p| p.unix_mode) {
+                Some(mode) => mode,
+                None if f.is_executable => 0o755,
+                None => 0o644,
+            },
+            size: digest.size_bytes,
+            digest: Some(digest),
+            executable: f.is_executable,
+            target: None,
+        });
+    }
+    for d in &dir.directories {
+        let digest = d.digest.clone().unwrap_or_default();
+        entries.push(Entry {
+            name: d.name.clone(),
+            kind: Kind::Directory,
+            mode: 0o755,
+            size: digest.size_bytes,
+            digest: Some(digest),
+            executable: false,
+            target: None,
+        });
+    }
+    for s in &dir.symlinks {
+        entries.push(Entry {
+            name: s.name.clone(),
+            kind: Kind::Symlink,
+            mode: 0o777,
+            size: s.target.len() as i64,
+            digest: None,
+            executable: false,
+            target: Some(s.target.clone()),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// the entry with the mode of a directory, see `dir_entry`
+pub fn with_dir_mode(client: &CacheClient, entry: Entry) -> Result<Entry> {
+    match &entry.digest {
+        Some(digest) if entry.kind == Kind::Directory => dir_entry(client, &entry.name, digest),
+        _ => Ok(entry),
+    }
+}
+
+/// the mode of a directory is in its own node properties, so the
+/// directory is fetched, and cached for when it is listed
+fn dir_entry(client: &CacheClient, name: &str, digest: &Digest) -> Result<Entry> {
+    let dir = client.read_dir(&digest.hash, digest.size_bytes)?;
+    Ok(Entry {
+        name: name.to_string(),
+        kind: Kind::Directory,
+        mode: dir
+            .node_properties
+            .as_ref()
+            .and_then(|p| p.unix_mode)
+            .unwrap_or(0o755),
+        size: digest.size_bytes,
+        digest: Some(digest.clone()),
+        executable: false,
+        target: None,
+    })
+}
+
+/// the entry with the entries under it, down to `depth` levels
+fn walk(client: &CacheClient, entry: Entry, depth: Option<usize>) -> Result<Node> {
+    let mut children = vec![];
+    if entry.kind == Kind::Directory && depth != Some(0) {
+        for child in list(client, &entry)? {
+            children.push(walk(client, child, depth.map(|d| d - 1))?);
+        }
+    }
+    Ok(Node { entry, children })
+}
+
+fn print_children(node: &Node, prefix: &str) {
+    for (i, child) in node.children.iter().enumerate() {
+        let last = i + 1 == node.children.len();
+        println!(
+            "{}{}{}",
+            prefix,
+            if last { "└── " } else { "├── " },
+            display_name(&child.entry)
+        );
+        let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        print_children(child, &prefix);
+    }
+}
+
+/// the name marked with the kind like `ls -F`, eg. `dir/` or `link -> target`
+fn display_name(e: &Entry) -> String {
+    match e.kind {
+        Kind::File if e.executable => format!("{}*", e.name),
+        Kind::File => e.name.clone(),
+        Kind::Directory => format!("{}/", e.name),
+        Kind::Symlink => format!("{} -> {}", e.name, e.target.as_deref().unwrap_or("")),
+    }
+}
+
+/// the mode like `ls -l`, eg. `drwxr-xr-x`
+fn mode_string(e: &Entry) -> String {
+    let mut s = String::from(match e.kind {
+        Kind::File => "-",
+        Kind::Directory => "d",
+        Kind::Symlink => "l",
+    });
+    for shift in [6, 3, 0] {
+        let bits = (e.mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
+        Directory, DirectoryNode, FileNode, NodeProperties, SymlinkNode,
+    };
+    use cfs::cas::fake::FakeCas;
+
+    #[test]
+    fn test_resolve_and_walk() {
+        let fake = FakeCas::new();
+        let hello = fake.insert(b"hello".to_vec());
+        let sub = fake.insert_dir(&Directory {
+            files: vec![FileNode {
+                name: String::from("run.sh"),
+                digest: Some(hello.clone()),
+                is_executable: true,
+                node_properties: None,
+            }],
+            node_properties: Some(NodeProperties {
+                unix_mode: Some(0o700),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let root = fake.insert_dir(&Directory {
+            files: vec![FileNode {
+                name: String::from("a.txt"),
+                digest: Some(hello),
+                is_executable: false,
+                node_properties: None,
+            }],
+            directories: vec![DirectoryNode {
+                name: String::from("sub"),
+                digest: Some(sub.clone()),
+            }],
+            symlinks: vec![SymlinkNode {
+                name: String::from("link"),
+                target: String::from("sub/run.sh"),
+                node_properties: None,
+            }],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+        let client = CacheClient::new().unwrap();
+
+        let names: Vec<_> = list(&client, &resolve(&client, &root, "", false).unwrap())
+            .unwrap()
+            .iter()
+            .map(display_name)
+            .collect();
+        assert_eq!(names, vec!["a.txt", "link -> sub/run.sh", "sub/"]);
+
+        let dir = resolve(&client, &root, "/sub/", false).unwrap();
+        assert_eq!(mode_string(&dir), "drwx------");
+        assert_eq!(dir.digest, Some(sub));
+
+        let file = resolve(&client, &root, "sub/run.sh", false).unwrap();
+        assert_eq!(file.size, 5);
+        assert_eq!(mode_string(&file), "-rwxr-xr-x");
+        assert!(resolve(&client, &root, "sub/missing", false).is_err());
+        assert!(resolve(&client, &root, "a.txt/b", false).is_err());
+        assert!(resolve(&client, &root, "../a.txt", false).is_err());
+        assert_eq!(
+            resolve(&client, &root, "sub/../a.txt", false).unwrap().name,
+            "a.txt"
+        );
+
+        let link = resolve(&client, &root, "link", false).unwrap();
+        assert_eq!(link.kind, Kind::Symlink);
+        let target = resolve(&client, &root, "link", true).unwrap();
+        assert_eq!(target.name, "run.sh");
+
+        let root_entry = resolve(&client, &root, "", false).unwrap();
+        let shallow = walk(&client, root_entry.clone(), Some(1)).unwrap();
+        assert_eq!(shallow.children.len(), 3);
+        assert!(shallow.children.iter().all(|c| c.children.is_empty()));
+        let full = walk(&client, root_entry, None).unwrap();
+        assert_eq!(full.children[2].children[0].entry.name, "run.sh");
+    }
+}
@@ -0,0 +1,30 @@
+use anyhow::Result;
+use cfs::cas;
+
+pub fn capabilities(json: bool) -> Result<()> {
+    let client = cas::blocking::Client::new()?;
+    let caps = client.capabilities();
+    if json {
+        println!("{}", serde_json::to_string_pretty(caps)?);
+        return Ok(());
+    }
+
+    println!(
+        "api version:             {} - {}",
+        caps.low_api_version, caps.high_api_version
+    );
+    println!(
+        "max batch size:          {} bytes",
+        caps.max_batch_total_size_bytes
+    );
+    println!(
+        "digest functions:        {}",
+        caps.digest_functions.join(", ")
+    );
+    println!("compressors:             {}", caps.compressors.join(", "));
+    println!(
+        "batch update compressors: {}",
+        caps.batch_update_compressors.join(", ")
+    );
+    Ok(())
+}
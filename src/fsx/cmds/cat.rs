@@ -0,0 +1,128 @@
+use super::browse::{resolve, Kind};
+use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
+use cfs::cas;
+use cfs::cas::blocking::{CacheClient, CHUNK_SIZE};
+use std::io::{self, Write};
+
+/// write the file at `path` of the tree to stdout, from `offset` and up
+/// to `length` bytes. Symlinks are followed inside the tree
+pub fn cat(digest: String, path: String, offset: u64, length: Option<u64>) -> Result<()> {
+    let root = cas::parse_digest(&digest)?;
+    let client = CacheClient::new()?;
+    let entry = resolve(&client, &root, &path, true)?;
+    let digest = match (entry.kind, entry.digest) {
+        (Kind::File, Some(digest)) => digest,
+        _ => return Err(anyhow::Error::msg(format!("{} is not a file", path))),
+    };
+
+    let stdout = io::stdout();
+    match copy_range(&client, &digest, offset, length, &mut stdout.lock()) {
+        // the reader is gone, eg. `fsx cat ... | head`
+        Err(e) if is_broken_pipe(&e) => Ok(()),
+        res => res,
+    }
+}
+
+/// copy the range of the blob by ByteStream reads of [`CHUNK_SIZE`], so
+/// that a large file is not held in memory
+fn copy_range<W: Write>(
+    client: &CacheClient,
+    digest: &Digest,
+    offset: u64,
+    length: Option<u64>,
+    out: &mut W,
+) -> Result<()> {
+    let size = digest.size_bytes as u64;
+    let end = match length {
+        Some(length) => offset.saturating_add(length).min(size),
+        None => size,
+    };
+
+    let mut pos = offset;
+    while pos < end {
+        let limit = CHUNK_SIZE.min(end - pos);
+        let data = client.fetch_range(&digest.hash, digest.size_bytes, pos, limit)?;
+        if data.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "{}/{} ended at {} bytes",
+                digest.hash, digest.size_bytes, pos
+            )));
+        }
+        out.write_all(&data)?;
+        pos += data.len() as u64;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
+        Directory, DirectoryNode, FileNode, SymlinkNode,
+    };
+    use cfs::cas::fake::FakeCas;
+
+    #[test]
+    fn test_cat_through_symlinks() {
+        let fake = FakeCas::new();
+        let hello = fake.insert(b"hello world".to_vec());
+        let sub = fake.insert_dir(&Directory {
+            files: vec![FileNode {
+                name: String::from("hello.txt"),
+                digest: Some(hello.clone()),
+                is_executable: false,
+                node_properties: None,
+            }],
+            ..Default::default()
+        });
+        let root = fake.insert_dir(&Directory {
+            directories: vec![DirectoryNode {
+                name: String::from("sub"),
+                digest: Some(sub),
+            }],
+            symlinks: vec![
+                SymlinkNode {
+                    name: String::from("dir"),
+                    target: String::from("./sub"),
+                    node_properties: None,
+                },
+                SymlinkNode {
+                    name: String::from("link"),
+                    target: String::from("dir/../sub/hello.txt"),
+                    node_properties: None,
+                },
+                SymlinkNode {
+                    name: String::from("loop"),
+                    target: String::from("loop"),
+                    node_properties: None,
+                },
+            ],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+        let client = CacheClient::new().unwrap();
+
+        let file = resolve(&client, &root, "link", true).unwrap();
+        assert_eq!(file.digest, Some(hello.clone()));
+        assert!(resolve(&client, &root, "loop", true).is_err());
+
+        let mut out = vec![];
+        copy_range(&client, &hello, 0, None, &mut out).unwrap();
+        assert_eq!(out, b"hello world");
+
+        let mut out = vec![];
+        copy_range(&client, &hello, 6, Some(100), &mut out).unwrap();
+        assert_eq!(out, b"world");
+
+        let mut out = vec![];
+        copy_range(&client, &hello, 20, None, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+}
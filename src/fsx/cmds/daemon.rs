@@ -0,0 +1,44 @@
+use anyhow::Result;
+use cfs::control::{self, Request, Response};
+
+/// Send the request to the daemon listening at the control socket and print the response
+pub fn daemon(socket: String, request: Request) -> Result<()> {
+    match control::call(&socket, &request)? {
+        Response::Status(status) => {
+            for m in &status.mounts {
+                println!("mount:       {} {}", m.mountpoint, m.digest);
+            }
+            println!(
+                "inodes:      {} ({} directories listed)",
+                status.inodes, status.directories
+            );
+            println!(
+                "cache:       {} blobs {} bytes",
+                status.cache.blobs, status.cache.bytes
+            );
+            println!(
+                "hit rate:    {:.1}% ({} hits, {} misses)",
+                status.cache.hit_rate() * 100.0,
+                status.cache.hits,
+                status.cache.misses
+            );
+            println!("in flight:   {}", status.cache.in_flight);
+        }
+        Response::Flushed { blobs, bytes } => {
+            println!("flushed {} blobs {} bytes", blobs, bytes);
+        }
+        Response::Prefetched { blobs, bytes } => {
+            println!("prefetched {} blobs {} bytes", blobs, bytes);
+        }
+        Response::Swapped {
+            digest,
+            invalidated,
+        } => {
+            println!("swapped root to {} ({} invalidated)", digest, invalidated);
+        }
+        Response::Committed { digest } => println!("{}", digest),
+        Response::Unmounted => println!("unmounted"),
+        Response::Error { message } => return Err(anyhow::Error::msg(message)),
+    }
+    Ok(())
+}
@@ -0,0 +1,394 @@
+use super::browse::{list_nodes, resolve, with_dir_mode, Entry, Kind};
+use anyhow::Result;
+use cfs::cas;
+use cfs::cas::blocking::CacheClient;
+use serde::Serialize;
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// max size of the files compared line by line with `--patch`
+const PATCH_MAX_SIZE: i64 = 256 * 1024;
+
+/// Status of a path between the two trees
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Added,
+    Removed,
+    /// the content of a file or the target of a symlink changed
+    Modified,
+    /// the mode or the executable bit changed, a file whose content
+    /// changed too has a `Modified` change as well
+    ModeChanged,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // pad, so that the paths line up
+        f.pad(match self {
+            Status::Added => "added",
+            Status::Removed => "removed",
+            Status::Modified => "modified",
+            Status::ModeChanged => "mode",
+        })
+    }
+}
+
+/// Change of a path, an added or removed directory is a single change
+/// for the whole subtree
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub path: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<Entry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<Entry>,
+    /// unified diff of a modified text file, with `--patch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+/// Stat counts the changes by status
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Stat {
+    pub added: u64,
+    pub removed: u64,
+    pub modified: u64,
+    pub mode_changed: u64,
+    /// directories with the same digest on both sides, not descended into
+    pub identical_subtrees: u64,
+}
+
+/// print the paths that differ between the trees of the root directories
+/// `old` and `new`
+pub fn diff(old: String, new: String, stat: bool, json: bool, patch: bool) -> Result<()> {
+    let old = cas::parse_digest(&old)?;
+    let new = cas::parse_digest(&new)?;
+    let client = CacheClient::new()?;
+
+    let mut differ = Differ::new(&client, patch);
+    differ.diff_roots(
+        resolve(&client, &old, "", false)?,
+        resolve(&client, &new, "", false)?,
+    )?;
+
+    match (stat, json) {
+        (true, true) => println!("{}", serde_json::to_string_pretty(&differ.stat())?),
+        (true, false) => print_stat(&differ.stat()),
+        (false, true) => println!("{}", serde_json::to_string_pretty(&differ.changes)?),
+        (false, false) => differ.changes.iter().for_each(print_change),
+    }
+    Ok(())
+}
+
+/// Differ walks both trees at once and skips the subtrees whose digests
+/// are the same on both sides, so only the directories that changed are
+/// fetched
+struct Differ<'a> {
+    client: &'a CacheClient,
+    patch: bool,
+    changes: Vec<Change>,
+    identical_subtrees: u64,
+}
+
+impl<'a> Differ<'a> {
+    fn new(client: &'a CacheClient, patch: bool) -> Differ<'a> {
+        Differ {
+            client,
+            patch,
+            changes: vec![],
+            identical_subtrees: 0,
+        }
+    }
+
+    fn diff_roots(&mut self, old: Entry, new: Entry) -> Result<()> {
+        if old.mode != new.mode {
+            self.push(
+                ".",
+                Status::ModeChanged,
+                Some(old.clone()),
+                Some(new.clone()),
+            )?;
+        }
+        self.diff_dirs(&old, &new, "")
+    }
+
+    /// the changes under the directories, in the order of the paths
+    fn diff_dirs(&mut self, old: &Entry, new: &Entry, prefix: &str) -> Result<()> {
+        if old.digest == new.digest {
+            self.identical_subtrees += 1;
+            return Ok(());
+        }
+
+        // the child directories are only fetched for their mode when they
+        // differ, the identical ones are skipped without being fetched
+        let mut old_entries = by_name(list_nodes(self.client, old)?);
+        let mut new_entries = by_name(list_nodes(self.client, new)?);
+        let names: BTreeSet<String> = old_entries
+            .keys()
+            .chain(new_entries.keys())
+            .cloned()
+            .collect();
+
+        for name in names {
+            let path = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            match (old_entries.remove(&name), new_entries.remove(&name)) {
+                (Some(o), Some(n)) if o.kind == Kind::Directory && o.digest == n.digest => {
+                    self.identical_subtrees += 1;
+                }
+                (Some(o), None) => {
+                    let o = with_dir_mode(self.client, o)?;
+                    self.push(&path, Status::Removed, Some(o), None)?;
+                }
+                (None, Some(n)) => {
+                    let n = with_dir_mode(self.client, n)?;
+                    self.push(&path, Status::Added, None, Some(n))?;
+                }
+                // eg. a file replaced by a directory
+                (Some(o), Some(n)) if o.kind != n.kind => {
+                    let o = with_dir_mode(self.client, o)?;
+                    let n = with_dir_mode(self.client, n)?;
+                    self.push(&path, Status::Removed, Some(o), None)?;
+                    self.push(&path, Status::Added, None, Some(n))?;
+                }
+                (Some(o), Some(n)) if o.kind == Kind::Directory => {
+                    let o = with_dir_mode(self.client, o)?;
+                    let n = with_dir_mode(self.client, n)?;
+                    if o.mode != n.mode {
+                        self.push(&path, Status::ModeChanged, Some(o.clone()), Some(n.clone()))?;
+                    }
+                    self.diff_dirs(&o, &n, &path)?;
+                }
+                // a file whose content and mode both changed is reported
+                // under both statuses
+                (Some(o), Some(n)) => {
+                    if o.digest != n.digest || o.target != n.target {
+                        self.push(&path, Status::Modified, Some(o.clone()), Some(n.clone()))?;
+                    }
+                    if o.mode != n.mode || o.executable != n.executable {
+                        self.push(&path, Status::ModeChanged, Some(o), Some(n))?;
+                    }
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        path: &str,
+        status: Status,
+        old: Option<Entry>,
+        new: Option<Entry>,
+    ) -> Result<()> {
+        let patch = match (&old, &new) {
+            (Some(o), Some(n)) if self.patch && status == Status::Modified => {
+                self.text_diff(path, o, n)?
+            }
+            _ => None,
+        };
+        self.changes.push(Change {
+            path: path.to_string(),
+            status,
+            old,
+            new,
+            patch,
+        });
+        Ok(())
+    }
+
+    /// the unified diff of two small text files, None for binary or large
+    /// files
+    fn text_diff(&self, path: &str, old: &Entry, new: &Entry) -> Result<Option<String>> {
+        let (old, new) = match (&old.digest, &new.digest) {
+            (Some(o), Some(n)) if old.kind == Kind::File => (o, n),
+            _ => return Ok(None),
+        };
+        if old.size_bytes > PATCH_MAX_SIZE || new.size_bytes > PATCH_MAX_SIZE {
+            return Ok(None);
+        }
+        let old = self.client.read_blob(&old.hash, old.size_bytes)?;
+        let new = self.client.read_blob(&new.hash, new.size_bytes)?;
+        let (old, new) = match (text(&old), text(&new)) {
+            (Some(o), Some(n)) => (o, n),
+            _ => return Ok(None),
+        };
+        Ok(Some(
+            TextDiff::from_lines(old, new)
+                .unified_diff()
+                .header(&format!("a/{}", path), &format!("b/{}", path))
+                .to_string(),
+        ))
+    }
+
+    fn stat(&self) -> Stat {
+        let mut stat = Stat {
+            identical_subtrees: self.identical_subtrees,
+            ..Default::default()
+        };
+        for change in &self.changes {
+            match change.status {
+                Status::Added => stat.added += 1,
+                Status::Removed => stat.removed += 1,
+                Status::Modified => stat.modified += 1,
+                Status::ModeChanged => stat.mode_changed += 1,
+            }
+        }
+        stat
+    }
+}
+
+fn by_name(entries: Vec<Entry>) -> BTreeMap<String, Entry> {
+    entries.into_iter().map(|e| (e.name.clone(), e)).collect()
+}
+
+/// the content as text, None when it is not UTF-8 or looks binary
+fn text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()
+}
+
+/// print the change like `modified  path/to/file`, with the modes of a
+/// mode change
+fn print_change(change: &Change) {
+    let suffix = match (change.status, &change.old, &change.new) {
+        (Status::Added, _, Some(n)) if n.kind == Kind::Directory => "/",
+        (Status::Removed, Some(o), _) if o.kind == Kind::Directory => "/",
+        _ => "",
+    };
+    match (&change.old, &change.new) {
+        (Some(o), Some(n)) if change.status == Status::ModeChanged => println!(
+            "{:<9} {}{} ({:o} -> {:o})",
+            change.status, change.path, suffix, o.mode, n.mode
+        ),
+        _ => println!("{:<9} {}{}", change.status, change.path, suffix),
+    }
+    if let Some(patch) = &change.patch {
+        print!("{}", patch);
+    }
+}
+
+fn print_stat(stat: &Stat) {
+    println!(
+        "{} added, {} removed, {} modified, {} mode changed, {} identical subtrees skipped",
+        stat.added, stat.removed, stat.modified, stat.mode_changed, stat.identical_subtrees
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
+        Digest, Directory, DirectoryNode, FileNode, NodeProperties,
+    };
+    use cfs::cas::fake::FakeCas;
+
+    fn file(name: &str, digest: &Digest, mode: Option<u32>) -> FileNode {
+        FileNode {
+            name: String::from(name),
+            digest: Some(digest.clone()),
+            is_executable: false,
+            node_properties: mode.map(|mode| NodeProperties {
+                unix_mode: Some(mode),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn dir(name: &str, digest: &Digest) -> DirectoryNode {
+        DirectoryNode {
+            name: String::from(name),
+            digest: Some(digest.clone()),
+        }
+    }
+
+    #[test]
+    fn test_diff_trees() {
+        let fake = FakeCas::new();
+        let hello = fake.insert(b"hello\nworld\n".to_vec());
+        let hullo = fake.insert(b"hullo\nworld\n".to_vec());
+        let binary = fake.insert(vec![0, 1, 2]);
+        let same = fake.insert_dir(&Directory {
+            files: vec![file("same.txt", &hello, None)],
+            ..Default::default()
+        });
+        let gone = fake.insert_dir(&Directory::default());
+        let old = fake.insert_dir(&Directory {
+            files: vec![
+                file("bin", &binary, None),
+                file("both.sh", &hello, Some(0o644)),
+                file("hello.txt", &hello, None),
+                file("run.sh", &hello, Some(0o644)),
+            ],
+            directories: vec![dir("gone", &gone), dir("same", &same)],
+            ..Default::default()
+        });
+        let new = fake.insert_dir(&Directory {
+            files: vec![
+                file("added.txt", &hello, None),
+                file("bin", &hello, None),
+                file("both.sh", &hullo, Some(0o755)),
+                file("hello.txt", &hullo, None),
+                file("run.sh", &hello, Some(0o755)),
+            ],
+            directories: vec![dir("same", &same)],
+            ..Default::default()
+        });
+        let _server = fake.start().unwrap();
+        let client = CacheClient::new().unwrap();
+
+        let mut differ = Differ::new(&client, true);
+        differ
+            .diff_roots(
+                resolve(&client, &old, "", false).unwrap(),
+                resolve(&client, &new, "", false).unwrap(),
+            )
+            .unwrap();
+        let changes: Vec<(&str, Status)> = differ
+            .changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.status))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("added.txt", Status::Added),
+                ("bin", Status::Modified),
+                ("both.sh", Status::Modified),
+                ("both.sh", Status::ModeChanged),
+                ("gone", Status::Removed),
+                ("hello.txt", Status::Modified),
+                ("run.sh", Status::ModeChanged),
+            ]
+        );
+
+        // no patch of a binary file
+        assert!(differ.changes[1].patch.is_none());
+        let patch = differ.changes[5].patch.as_deref().unwrap();
+        assert!(patch.starts_with("--- a/hello.txt\n+++ b/hello.txt\n"));
+        assert!(patch.contains("-hello\n+hullo\n"));
+
+        // the identical subtree is not fetched
+        assert!(!client.contains(&same.hash));
+
+        assert_eq!(
+            differ.stat(),
+            Stat {
+                added: 1,
+                removed: 1,
+                modified: 3,
+                mode_changed: 2,
+                identical_subtrees: 1,
+            }
+        );
+    }
+}
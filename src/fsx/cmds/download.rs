@@ -1,21 +1,67 @@
 use anyhow::Result;
+use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
 use cfs::cas;
-use std::fs::File;
+use cfs::cas::blocking::CacheClient;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
 
-pub fn download(path: String, digest: String) -> Result<()> {
+pub fn download(path: String, digest: String, recursive: bool) -> Result<()> {
     println!("Download digest {} at {}", digest, path);
-    let tokens: Vec<_> = digest.split("/").collect();
-    if tokens.len() != 2 {
-        return Err(anyhow::Error::msg("malformed digest"));
-    }
-
-    let hash = tokens[0];
-    let size = tokens[1].parse::<i64>().unwrap();
+    let digest = cas::parse_digest(&digest)?;
 
-    let mut cas_client = cas::blocking::CacheClient::new()?;
-    let blob = cas_client.read_blob(hash, size)?;
+    let cas_client = cas::blocking::CacheClient::new()?;
+    if recursive {
+        let (files, bytes) = download_dir(&cas_client, &digest, Path::new(&path))?;
+        println!("Downloaded {} files of {} bytes", files, bytes);
+        return Ok(());
+    }
+    let blob = cas_client.read_blob(&digest.hash, digest.size_bytes)?;
 
     let mut file = File::create(path)?;
     file.write_all(&blob).map_err(|e| anyhow::Error::new(e))
 }
+
+/// download the tree under the directory into path, the files of each
+/// directory are read in batches. Returns the number of files and bytes
+fn download_dir(cas_client: &CacheClient, digest: &Digest, path: &Path) -> Result<(u64, u64)> {
+    fs::create_dir_all(path)?;
+    let dir = cas_client.get_dir(&digest.hash, digest.size_bytes)?;
+
+    let digests: Vec<Digest> = dir.files.iter().filter_map(|f| f.digest.clone()).collect();
+    let blobs = cas_client.read_blobs(&digests)?;
+
+    let mut files = 0;
+    let mut bytes = 0;
+    for f in &dir.files {
+        let blob = match f.digest.as_ref().and_then(|d| blobs.get(&d.hash)) {
+            Some(blob) => blob,
+            None => continue,
+        };
+        let file_path = path.join(&f.name);
+        File::create(&file_path)?.write_all(blob)?;
+
+        let mode = match f.node_properties.as_ref().and_then(|p| p.unix_mode) {
+            Some(mode) => mode,
+            None if f.is_executable => 0o755,
+            None => 0o644,
+        };
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(mode))?;
+        files += 1;
+        bytes += blob.len() as u64;
+    }
+
+    for s in &dir.symlinks {
+        symlink(&s.target, path.join(&s.name))?;
+    }
+
+    for d in &dir.directories {
+        if let Some(digest) = &d.digest {
+            let (f, b) = download_dir(cas_client, digest, &path.join(&d.name))?;
+            files += f;
+            bytes += b;
+        }
+    }
+    Ok((files, bytes))
+}
@@ -1,9 +1,20 @@
+mod browse;
+mod capabilities;
+mod cat;
+mod daemon;
+mod diff;
 mod download;
 mod mount;
+mod progress;
 mod test;
 mod traverse;
 mod upload;
 
+pub use browse::{ls, stat, tree};
+pub use capabilities::capabilities;
+pub use cat::cat;
+pub use daemon::daemon;
+pub use diff::diff;
 pub use download::download;
 pub use mount::mount;
 pub use test::test;
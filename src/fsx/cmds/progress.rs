@@ -0,0 +1,227 @@
+use cfs::cas::blocking::UploadStats;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress of an upload, counted by the hashing threads and the upload
+/// pipeline and drawn on stderr while the upload runs
+pub struct Progress {
+    /// files and bytes found in the tree being hashed
+    files: AtomicU64,
+    bytes: AtomicU64,
+    files_hashed: AtomicU64,
+    bytes_hashed: AtomicU64,
+    upload: Arc<UploadStats>,
+    start: Instant,
+    hash_time: Mutex<Option<Duration>>,
+    interrupted: AtomicBool,
+    done: AtomicBool,
+}
+
+impl Progress {
+    pub fn new() -> Arc<Progress> {
+        Arc::new(Progress {
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            files_hashed: AtomicU64::new(0),
+            bytes_hashed: AtomicU64::new(0),
+            upload: Arc::new(UploadStats::default()),
+            start: Instant::now(),
+            hash_time: Mutex::new(None),
+            interrupted: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+        })
+    }
+
+    /// the stats of the upload pipeline
+    pub fn upload_stats(&self) -> Arc<UploadStats> {
+        self.upload.clone()
+    }
+
+    /// the files found to hash
+    pub fn found(&self, files: u64, bytes: u64) {
+        self.files.fetch_add(files, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn hashed(&self, bytes: u64) {
+        self.files_hashed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_hashed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// stop hashing, the files already queued are still uploaded
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// all of the files are hashed, the rest of the time is spent uploading
+    pub fn hash_done(&self) {
+        self.hash_time
+            .lock()
+            .unwrap()
+            .get_or_insert(self.start.elapsed());
+    }
+
+    /// draw the progress bar till [`Progress::finish`], the bar follows
+    /// the bytes hashed and then the bytes uploaded. It is hidden when
+    /// stderr is not a terminal
+    pub fn draw(self: &Arc<Self>) -> JoinHandle<()> {
+        let progress = self.clone();
+        thread::spawn(move || {
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{prefix:>6} [{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} \
+                         {binary_bytes_per_sec} eta {eta} {msg}",
+                    )
+                    .progress_chars("=> "),
+            );
+            bar.set_prefix("hash");
+            let mut hashing = true;
+            while !progress.done.load(Ordering::Relaxed) {
+                let counts = progress.upload.snapshot();
+                if hashing && progress.hash_time.lock().unwrap().is_some() {
+                    hashing = false;
+                    bar.set_prefix("upload");
+                    bar.reset_eta();
+                }
+                if hashing {
+                    bar.set_length(progress.bytes.load(Ordering::Relaxed));
+                    bar.set_position(progress.bytes_hashed.load(Ordering::Relaxed));
+                    bar.set_message(format!(
+                        "{}/{} files, {} blobs missing, {} uploaded",
+                        progress.files_hashed.load(Ordering::Relaxed),
+                        progress.files.load(Ordering::Relaxed),
+                        counts.missing_blobs,
+                        counts.uploaded_blobs
+                    ));
+                } else {
+                    bar.set_length(counts.missing_bytes);
+                    bar.set_position(counts.uploaded_bytes);
+                    bar.set_message(format!(
+                        "{}/{} blobs uploaded",
+                        counts.uploaded_blobs, counts.missing_blobs
+                    ));
+                }
+                thread::sleep(REFRESH_INTERVAL);
+            }
+            bar.finish_and_clear();
+        })
+    }
+
+    /// stop drawing and sum up the upload of the root `digest`
+    pub fn finish(&self, digest: String) -> Summary {
+        self.hash_done();
+        self.done.store(true, Ordering::Relaxed);
+
+        let counts = self.upload.snapshot();
+        let dedup_ratio = if counts.checked_bytes == 0 {
+            0.0
+        } else {
+            1.0 - counts.missing_bytes as f64 / counts.checked_bytes as f64
+        };
+        Summary {
+            digest,
+            interrupted: self.is_interrupted(),
+            files: self.files_hashed.load(Ordering::Relaxed),
+            bytes: self.bytes_hashed.load(Ordering::Relaxed),
+            checked_blobs: counts.checked_blobs,
+            checked_bytes: counts.checked_bytes,
+            missing_blobs: counts.missing_blobs,
+            missing_bytes: counts.missing_bytes,
+            uploaded_blobs: counts.uploaded_blobs,
+            uploaded_bytes: counts.uploaded_bytes,
+            failed_blobs: counts.failed_blobs,
+            dedup_ratio,
+            hash_secs: self
+                .hash_time
+                .lock()
+                .unwrap()
+                .unwrap_or_default()
+                .as_secs_f64(),
+            find_missing_secs: counts.find_missing_time.as_secs_f64(),
+            upload_secs: counts.upload_time.as_secs_f64(),
+            total_secs: self.start.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Summary of an upload, the sizes are before compression
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// the root digest, empty when interrupted
+    pub digest: String,
+    /// the upload was stopped by a signal, the counts are partial
+    pub interrupted: bool,
+    /// files hashed
+    pub files: u64,
+    pub bytes: u64,
+    /// blobs checked with FindMissingBlobs, the files and the directories
+    pub checked_blobs: u64,
+    pub checked_bytes: u64,
+    pub missing_blobs: u64,
+    pub missing_bytes: u64,
+    pub uploaded_blobs: u64,
+    pub uploaded_bytes: u64,
+    pub failed_blobs: u64,
+    /// share of the checked bytes already in CAS
+    pub dedup_ratio: f64,
+    /// time to hash the tree, the uploads start meanwhile
+    pub hash_secs: f64,
+    /// time spent in FindMissingBlobs
+    pub find_missing_secs: f64,
+    /// time from the first upload to the end of the last one
+    pub upload_secs: f64,
+    pub total_secs: f64,
+}
+
+impl Summary {
+    pub fn print(&self) {
+        if self.interrupted {
+            eprintln!("interrupted, the counts are partial");
+        } else {
+            eprintln!("digest:       {}", self.digest);
+        }
+        eprintln!(
+            "hashed:       {} files, {} in {:.1}s",
+            self.files,
+            HumanBytes(self.bytes),
+            self.hash_secs
+        );
+        eprintln!(
+            "checked:      {} blobs, {} in {:.1}s",
+            self.checked_blobs,
+            HumanBytes(self.checked_bytes),
+            self.find_missing_secs
+        );
+        eprintln!(
+            "missing:      {} blobs, {}",
+            self.missing_blobs,
+            HumanBytes(self.missing_bytes)
+        );
+        eprintln!(
+            "uploaded:     {} blobs, {} in {:.1}s",
+            self.uploaded_blobs,
+            HumanBytes(self.uploaded_bytes),
+            self.upload_secs
+        );
+        if self.failed_blobs > 0 {
+            eprintln!("failed:       {} blobs", self.failed_blobs);
+        }
+        eprintln!(
+            "dedup:        {:.1}% of the bytes already in CAS",
+            self.dedup_ratio * 100.0
+        );
+        eprintln!("elapsed:      {:.1}s", self.total_secs);
+    }
+}
@@ -1,19 +1,20 @@
+use super::progress::Progress;
 use super::upload::BlobUploader;
 use anyhow::Result;
 use bazel_remote_apis_rs::build::bazel::remote::execution::v2::{
     Digest, Directory, DirectoryNode, FileNode, NodeProperties, SymlinkNode,
 };
-use cfs::hash::{sha256, sha256_read};
+use cfs::hash::DigestFunction;
 use cfs::lfs::LfsFile;
 use prost::Message;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::prelude::*;
-use std::io::SeekFrom;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
 use walkdir::WalkDir;
 
 #[derive(Debug)]
@@ -23,11 +24,18 @@ pub struct FileDigest {
 }
 
 impl FileDigest {
-    pub fn new(path: &Path) -> Result<FileDigest> {
+    pub fn new(path: &Path, digest_function: DigestFunction) -> Result<FileDigest> {
         let mut file = File::open(path)
             .map_err(|e| anyhow::Error::msg(format!("failed to read path: {:?} {:?}", path, e)))?;
 
         if let Ok(lfs_file) = LfsFile::new(&mut file) {
+            // the lfs pointer only carries the sha256 of the object
+            if digest_function != DigestFunction::Sha256 {
+                return Err(anyhow::Error::msg(format!(
+                    "git lfs file {:?} requires the sha256 digest function",
+                    path
+                )));
+            }
             return Ok(FileDigest {
                 // TODO: fix the path to use git lfs storage path
                 // and fetch the object when it is missing with
@@ -39,9 +47,7 @@ impl FileDigest {
                 },
             });
         }
-        // reset the read offset to 0
-        file.seek(SeekFrom::Start(0))?;
-        let (hash, size) = sha256_read(&mut file)?;
+        let (hash, size) = digest_function.hash_file(path)?;
         Ok(FileDigest {
             path: path.to_path_buf(),
             digest: Digest {
@@ -57,16 +63,25 @@ pub struct Traverse {
     digests: HashMap<OsString, Digest>,
     /// Uploader uploads the blobs
     uploader: Box<dyn BlobUploader>,
+    /// the hash function of the digests
+    digest_function: DigestFunction,
+    progress: Arc<Progress>,
 }
 
 impl Traverse {
-    pub fn new(uploader: Box<dyn BlobUploader>) -> Result<Traverse> {
+    pub fn new(
+        uploader: Box<dyn BlobUploader>,
+        digest_function: DigestFunction,
+        progress: Arc<Progress>,
+    ) -> Result<Traverse> {
         //let cas_client = blocking::Client::new()?;
 
         Ok(Traverse {
             digests: HashMap::new(),
             //cas_client: cas_client,
             uploader: uploader,
+            digest_function,
+            progress,
         })
     }
 
@@ -85,7 +100,7 @@ impl Traverse {
     fn load_file_hashs(&mut self, path: &Path) -> Result<HashMap<OsString, Digest>> {
         // TODO: consolidate the list in two places
         let ignored_file_names = vec![".git"];
-        let paths: Vec<_> = WalkDir::new(path)
+        let entries: Vec<_> = WalkDir::new(path)
             .into_iter()
             .filter_entry(|e| {
                 !e.file_name()
@@ -95,21 +110,39 @@ impl Traverse {
             })
             .filter_map(|e| e.ok())
             .filter(|e| e.file_type().is_file())
-            .map(|e| e.into_path())
             .collect();
+        let bytes = entries
+            .iter()
+            .filter_map(|e| e.metadata().ok())
+            .map(|md| md.len())
+            .sum();
+        self.progress.found(entries.len() as u64, bytes);
+        let paths: Vec<_> = entries.into_iter().map(|e| e.into_path()).collect();
+
+        // hash the files in parallel, each file is queued for upload as
+        // soon as it is hashed so that the uploads start while the rest of
+        // the tree is still being hashed
+        let digest_function = self.digest_function;
+        let uploader = &self.uploader;
+        let progress = &self.progress;
+        let files: Vec<_> = paths
+            .par_iter()
+            .map(|p| -> Result<FileDigest> {
+                if progress.is_interrupted() {
+                    return Err(anyhow::Error::msg("interrupted"));
+                }
+                let f = FileDigest::new(p, digest_function)?;
+                progress.hashed(f.digest.size_bytes as u64);
+                uploader.upload_file(&f.digest, &f.path)?;
+                Ok(f)
+            })
+            .collect::<Result<_>>()?;
 
-        let files: Vec<_> = paths.par_iter().map(|p| FileDigest::new(p)).collect();
         let mut res = HashMap::new();
-        for file in files {
-            let f = file?;
+        for f in files {
             res.insert(f.path.into_os_string(), f.digest);
         }
 
-        // upload the blobs
-        for (path, digest) in &res {
-            self.uploader.upload_file(digest, Path::new(path));
-        }
-
         Ok(res)
     }
 
@@ -125,14 +158,14 @@ impl Traverse {
     fn create_directory_digest(&mut self, dir: Directory) -> Result<Digest> {
         let mut buff = vec![];
         dir.encode(&mut buff)?;
-        let hash = sha256(&buff);
+        let hash = self.digest_function.hash(&buff);
         let size = buff.len() as i64;
         let digest = Digest {
             hash: hash,
             size_bytes: size,
         };
 
-        self.uploader.upload_blob(&digest, buff);
+        self.uploader.upload_blob(&digest, buff)?;
 
         Ok(digest)
     }
@@ -226,22 +259,22 @@ impl Traverse {
                                 let dir = self.create_directory(&path)?;
                                 match self.create_directory_node(name.to_string(), dir) {
                                     Ok(dir_node) => directories.push(dir_node),
-                                    Err(e) => println!("create_directory_node failed {}", e),
+                                    Err(e) => warn!("create_directory_node failed {}", e),
                                 }
                             }
                         }
                     } else if file_type.is_file() {
                         match self.create_file_node(&path) {
                             Ok(file_node) => files.push(file_node),
-                            Err(e) => println!("create_file_node failed {}", e),
+                            Err(e) => warn!("create_file_node failed {}", e),
                         };
                     } else if file_type.is_symlink() {
                         match self.create_symlink_node(&path) {
                             Ok(link_node) => symlinks.push(link_node),
-                            Err(e) => println!("create_symlink_node failed {}", e),
+                            Err(e) => warn!("create_symlink_node failed {}", e),
                         };
                     } else {
-                        println!("unknow path type: {:?}", path);
+                        warn!("unknow path type: {:?}", path);
                     }
                 }
             }
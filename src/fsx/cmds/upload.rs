@@ -1,70 +1,200 @@
+use super::progress::{Progress, Summary};
 use super::traverse::Traverse;
 use anyhow::Result;
 use bazel_remote_apis_rs::build::bazel::remote::execution::v2::Digest;
 use cfs::cas::blocking;
-use cfs::hash::sha256_read;
-use std::fs::{self, File};
+use cfs::cas::blocking::ClientOptions;
+use cfs::hash::DigestFunction;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::{Handle, Signals};
+use std::fs;
 use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
 use tokio::sync::mpsc;
+use tracing::warn;
 
 /// Uploads the path to CAS. The path being a file or a directory.
 ///
-/// As a result of the upload, creates the sha256 hash for a given path
+/// As a result of the upload, creates the hash for a given path
 /// following the bazel remote api direcotry's canonicalized structure
 /// [https://github.com/bazelbuild/remote-apis/blob/main/build/bazel/remote/execution/v2/remote_execution.proto#L789]
-pub fn upload<P: AsRef<Path>>(path: P, out: Option<P>, dry_run: bool) -> Result<()> {
+///
+/// The progress is drawn on stderr and summed up at the end, or printed
+/// in JSON with `json`
+pub fn upload<P: AsRef<Path>>(
+    path: P,
+    out: Option<P>,
+    dry_run: bool,
+    json: bool,
+    options: ClientOptions,
+) -> Result<()> {
+    let progress = Progress::new();
+    let _signals = handle_signals(progress.clone(), json)?;
+    let summary = push(path.as_ref(), dry_run, options, &progress)?;
+    if summary.interrupted {
+        print_summary(&summary, json)?;
+        return Err(anyhow::Error::msg("upload interrupted"));
+    }
+    // the digest is of no use when some of its blobs are not in CAS
+    if summary.failed_blobs > 0 {
+        print_summary(&summary, json)?;
+        return Err(anyhow::Error::msg(format!(
+            "failed to upload {} blobs",
+            summary.failed_blobs
+        )));
+    }
+
+    if let Some(out_path) = &out {
+        //println!("Writing at {}", out_path.as_ref().display());
+        fs::write(out_path, &summary.digest)
+            .map_err(|e| anyhow::Error::msg(format!("failed to write digest {}", e)))?;
+    }
+    print_summary(&summary, json)?;
+    // the digest is the only output on stdout so that it could be piped
+    if !json && out.is_none() {
+        println!("{}", summary.digest);
+    }
+    Ok(())
+}
+
+fn print_summary(summary: &Summary, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(summary)?);
+    } else {
+        summary.print();
+    }
+    Ok(())
+}
+
+/// The first SIGINT or SIGTERM stops the hashing and waits for the blobs
+/// already queued to be uploaded, the second one exits right away. Both
+/// print the partial summary
+///
+/// The handlers are unregistered when the returned guard is dropped
+fn handle_signals(progress: Arc<Progress>, json: bool) -> Result<SignalsGuard> {
+    let mut signals = Signals::new(&[SIGINT, SIGTERM])?;
+    let handle = signals.handle();
+    let thread = std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if !progress.is_interrupted() {
+                warn!("interrupted, waiting for the queued uploads, interrupt again to exit now");
+                progress.interrupt();
+                continue;
+            }
+            let _ = print_summary(&progress.finish(String::new()), json);
+            std::process::exit(130);
+        }
+    });
+    Ok(SignalsGuard {
+        handle,
+        thread: Some(thread),
+    })
+}
+
+/// SignalsGuard stops the thread handling the signals once dropped
+struct SignalsGuard {
+    handle: Handle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for SignalsGuard {
+    fn drop(&mut self) {
+        self.handle.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// upload the path while drawing the progress, returns the summary
+fn push(
+    path: &Path,
+    dry_run: bool,
+    options: ClientOptions,
+    progress: &Arc<Progress>,
+) -> Result<Summary> {
+    let drawing = progress.draw();
+    let res = push_path(path, dry_run, options, progress);
+    let summary = progress.finish(res.as_ref().map_or(String::new(), |d| d.clone()));
+    let _ = drawing.join();
+    match res {
+        Err(_) if summary.interrupted => Ok(summary),
+        res => res.map(|_| summary),
+    }
+}
+
+/// upload the path and returns its digest
+fn push_path(
+    path: &Path,
+    dry_run: bool,
+    options: ClientOptions,
+    progress: &Arc<Progress>,
+) -> Result<String> {
+    // the digests are computed before anything is sent, so the digest
+    // function has to be agreed with the server up front
+    let digest_function = match options.digest_function {
+        Some(f) => f,
+        None if dry_run => DigestFunction::default(),
+        None => blocking::get_server_capabilities()?.digest_function(None)?,
+    };
+    let options = ClientOptions {
+        digest_function: Some(digest_function),
+        ..options
+    };
+
     // Since receiver shutdown depends on all senders being out of scope,
     // need to create the receiver independent of the uploader (which uses sender)
-    // to avoid cyclic dependency when joining the handle
-    // Create the receiver regardless for now. Optimize later.
-    let (send, handle) = blocking::spawn_receiver();
-    let uploader: Box<dyn BlobUploader> = if dry_run {
-        Box::new(NoopBlobUploader {})
+    // to avoid cyclic dependency when joining the handle.
+    // A dry run sends nothing, so it doesn't connect to the server at all
+    let (uploader, handle): (Box<dyn BlobUploader>, _) = if dry_run {
+        (Box::new(NoopBlobUploader {}), None)
     } else {
-        Box::new(CasBlobUploader::new(send)?)
+        let (send, handle) = blocking::spawn_receiver(options, progress.upload_stats())?;
+        (Box::new(CasBlobUploader::new(send)?), Some(handle))
     };
 
-    let path = path.as_ref();
     //println!("Uploading {}", path.display());
 
     let digest = if path.is_dir() {
-        upload_dir(uploader, path)
+        upload_dir(uploader, path, digest_function, progress.clone())
     } else if path.is_file() {
-        upload_file(uploader, path)
+        upload_file(uploader, path, digest_function, progress)
     } else {
         Err(anyhow::Error::msg("unsupported file type"))
-    }?;
+    };
+    progress.hash_done();
 
-    let res = handle.join();
-    if res.is_err() {
-        return Err(anyhow::Error::msg(format!(
-            "failed to join handle {:?}",
-            res.unwrap_err()
-        )));
+    // the blobs already queued are uploaded even when the hashing failed
+    // or was interrupted, the uploader is dropped by now
+    let res = handle.map(|h| h.join());
+    let digest = digest?;
+    if let Some(Err(e)) = res {
+        return Err(anyhow::Error::msg(format!("failed to join handle {:?}", e)));
     }
 
-    let digest_str = format!("{}/{}", digest.hash, digest.size_bytes);
-    match out {
-        Some(out_path) => {
-            //println!("Writing at {}", out_path.as_ref().display());
-            fs::write(out_path, digest_str)
-                .map_err(|e| anyhow::Error::msg(format!("failed to write digest {}", e)))
-        }
-        None => {
-            println!("{}", digest_str);
-            Ok(())
-        }
-    }
+    Ok(format!("{}/{}", digest.hash, digest.size_bytes))
 }
 
-fn upload_dir(uploader: Box<dyn BlobUploader>, path: &Path) -> Result<Digest> {
-    let mut t = Traverse::new(uploader)?;
+fn upload_dir(
+    uploader: Box<dyn BlobUploader>,
+    path: &Path,
+    digest_function: DigestFunction,
+    progress: Arc<Progress>,
+) -> Result<Digest> {
+    let mut t = Traverse::new(uploader, digest_function, progress)?;
     t.root_digest(path)
 }
 
-fn upload_file(mut uploader: Box<dyn BlobUploader>, path: &Path) -> Result<Digest> {
-    let mut f = File::open(path)?;
-    let (hash, size) = sha256_read(&mut f)?;
+fn upload_file(
+    uploader: Box<dyn BlobUploader>,
+    path: &Path,
+    digest_function: DigestFunction,
+    progress: &Progress,
+) -> Result<Digest> {
+    progress.found(1, fs::metadata(path)?.len());
+    let (hash, size) = digest_function.hash_file(path)?;
+    progress.hashed(size as u64);
     let digest = Digest {
         hash: hash,
         size_bytes: size as i64,
@@ -73,23 +203,26 @@ fn upload_file(mut uploader: Box<dyn BlobUploader>, path: &Path) -> Result<Diges
 }
 
 /// BlobkUpload is the trait for uploading blobs
-pub trait BlobUploader {
+///
+/// Uploaders are shared by the hashing threads, each file is uploaded
+/// from the thread that hashed it
+pub trait BlobUploader: Send + Sync {
     /// Upload a blob to the backend storage
-    fn upload_blob(&mut self, digest: &Digest, buff: Vec<u8>) -> Result<()>;
+    fn upload_blob(&self, digest: &Digest, buff: Vec<u8>) -> Result<()>;
 
     /// Upload a file to the backend storage given the file path
-    fn upload_file(&mut self, digest: &Digest, path: &Path) -> Result<()>;
+    fn upload_file(&self, digest: &Digest, path: &Path) -> Result<()>;
 }
 
 pub struct NoopBlobUploader {}
 
 impl BlobUploader for NoopBlobUploader {
-    fn upload_blob(&mut self, digest: &Digest, _: Vec<u8>) -> Result<()> {
+    fn upload_blob(&self, digest: &Digest, _: Vec<u8>) -> Result<()> {
         //println!("skip upload blob {:?}", digest);
         Ok(())
     }
 
-    fn upload_file(&mut self, _: &Digest, path: &Path) -> Result<()> {
+    fn upload_file(&self, _: &Digest, path: &Path) -> Result<()> {
         //println!("skip upload file {:?}", path);
         Ok(())
     }
@@ -111,11 +244,118 @@ impl CasBlobUploader {
 }
 
 impl BlobUploader for CasBlobUploader {
-    fn upload_blob(&mut self, digest: &Digest, buff: Vec<u8>) -> Result<()> {
-        self.cas_client.write_blob(digest, buff)
+    fn upload_blob(&self, digest: &Digest, buff: Vec<u8>) -> Result<()> {
+        self.cas_client
+            .write_blob(digest, buff)
+            .map_err(|e| e.into())
+    }
+
+    fn upload_file(&self, digest: &Digest, path: &Path) -> Result<()> {
+        self.cas_client
+            .write_file(digest, path)
+            .map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::download::download;
+    use super::*;
+    use cfs::cas::fake::{FakeCas, Faults};
+    use cfs::test_util::TempDir;
+    use tonic::Code;
+
+    #[test]
+    fn test_upload_and_download_dir() {
+        let fake = FakeCas::new();
+        let _server = fake.start().unwrap();
+
+        let root = TempDir::new("upload").unwrap();
+        let src = root.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        fs::write(src.join("sub").join("b.txt"), "world").unwrap();
+        let out = root.path().join("digest");
+
+        upload(&src, Some(&out), false, false, ClientOptions::default()).unwrap();
+        let digest = fs::read_to_string(&out).unwrap();
+        let dst = root.path().join("dst");
+        download(dst.to_string_lossy().to_string(), digest, true).unwrap();
+
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "hello");
+        assert_eq!(
+            fs::read_to_string(dst.join("sub").join("b.txt")).unwrap(),
+            "world"
+        );
+    }
+
+    #[test]
+    fn test_upload_faults() {
+        let fake = FakeCas::new();
+        let _server = fake.start().unwrap();
+
+        let root = TempDir::new("upload-faults").unwrap();
+        let src = root.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+
+        // the blobs are uploaded when they cannot be checked
+        fake.set_faults(Faults {
+            find_missing_error: Some(Code::Internal),
+            ..Default::default()
+        });
+        upload(&src, None, false, false, ClientOptions::default()).unwrap();
+        assert!(fake.get(&DigestFunction::Sha256.hash(b"hello")).is_some());
+
+        // no digest is given out when a blob fails to upload
+        fs::write(src.join("b.txt"), "world").unwrap();
+        let mut faults = Faults::default();
+        faults
+            .batch_errors
+            .insert(DigestFunction::Sha256.hash(b"world"), Code::Internal);
+        fake.set_faults(faults);
+        let out = root.path().join("digest");
+        assert!(upload(&src, Some(&out), false, false, ClientOptions::default()).is_err());
+        assert!(!out.exists());
     }
 
-    fn upload_file(&mut self, digest: &Digest, path: &Path) -> Result<()> {
-        self.cas_client.write_file(digest, path)
+    #[test]
+    fn test_upload_summary() {
+        let fake = FakeCas::new();
+        let _server = fake.start().unwrap();
+
+        let root = TempDir::new("summary").unwrap();
+        let src = root.path();
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        fs::write(src.join("sub").join("b.txt"), "world").unwrap();
+
+        // a dry run hashes without talking to the server
+        let dry = push(src, true, ClientOptions::default(), &Progress::new()).unwrap();
+        assert_eq!(dry.files, 2);
+        assert_eq!(fake.calls("GetCapabilities"), 0);
+        assert_eq!(fake.calls("FindMissingBlobs"), 0);
+
+        let first = push(src, false, ClientOptions::default(), &Progress::new()).unwrap();
+        assert_eq!(first.files, 2);
+        assert_eq!(first.bytes, 10);
+        // the two files and the two directories
+        assert_eq!(first.uploaded_blobs, 4);
+        assert_eq!(first.dedup_ratio, 0.0);
+
+        // nothing to upload the second time
+        let second = push(src, false, ClientOptions::default(), &Progress::new()).unwrap();
+        assert_eq!(second.digest, first.digest);
+        assert_eq!(second.checked_blobs, 4);
+        assert_eq!(second.uploaded_blobs, 0);
+        assert_eq!(second.dedup_ratio, 1.0);
+
+        // interrupted before any file is hashed
+        let progress = Progress::new();
+        progress.interrupt();
+        let partial = push(src, false, ClientOptions::default(), &progress).unwrap();
+        assert!(partial.interrupted);
+        assert_eq!(partial.digest, "");
+        assert_eq!(partial.files, 0);
     }
 }
@@ -1,4 +1,9 @@
 use anyhow::Result;
+use cfs::cas::blocking::{ClientOptions, UploadOptions};
+use cfs::cas::compression::{self, Compression, CompressionOptions};
+use cfs::control::{Request, DEFAULT_SOCKET_PATH};
+use cfs::hash::DigestFunction;
+use cfs::logging::{self, LogFormat};
 use clap::{Parser, Subcommand};
 
 mod cmds;
@@ -10,6 +15,10 @@ mod cmds;
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Format of the logs: text or json. The level is set with RUST_LOG
+    #[clap(long, global = true, default_value = "text")]
+    log_format: LogFormat,
 }
 
 #[derive(Debug, Subcommand)]
@@ -27,6 +36,36 @@ enum Commands {
         /// Generate the root digest without the actual upload
         #[clap(long)]
         dry_run: bool,
+
+        /// Compression of the blobs being uploaded: auto, zstd or none.
+        /// auto uses zstd when the server supports it
+        #[clap(long, default_value = "auto")]
+        compression: Compression,
+
+        /// zstd compression level
+        #[clap(long, default_value_t = compression::DEFAULT_LEVEL)]
+        compression_level: i32,
+
+        /// The hash function of the digests: sha256 or blake3.
+        /// Picked from the server capabilities by default
+        #[clap(long)]
+        digest_function: Option<DigestFunction>,
+
+        /// Max number of concurrent ByteStream writes
+        #[clap(long, default_value_t = UploadOptions::default().streams)]
+        upload_streams: usize,
+
+        /// Max number of concurrent BatchUpdateBlobs requests
+        #[clap(long, default_value_t = UploadOptions::default().batches)]
+        upload_batches: usize,
+
+        /// Max bytes of the blobs being uploaded at a time
+        #[clap(long, default_value_t = UploadOptions::default().max_inflight_bytes)]
+        upload_inflight_bytes: u64,
+
+        /// Print the summary of the upload in JSON, eg. for CI
+        #[clap(long)]
+        json: bool,
     },
 
     /// Download file or directory from CAS
@@ -37,6 +76,10 @@ enum Commands {
 
         /// The digest of the content
         digest: String,
+
+        /// The digest is a directory, download the whole tree under it
+        #[clap(short, long)]
+        recursive: bool,
     },
 
     /// Mount the source 
@@ -49,19 +92,219 @@ enum Commands {
         digest: String,
     },
 
+    /// List a directory of a tree in CAS
+    #[clap(arg_required_else_help = true)]
+    Ls {
+        /// The digest of the root directory
+        digest: String,
+
+        /// The path under the root, the root by default
+        path: Option<String>,
+
+        /// Print in JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Print a tree in CAS
+    #[clap(arg_required_else_help = true)]
+    Tree {
+        /// The digest of the root directory
+        digest: String,
+
+        /// Max depth of the directories to descend into
+        #[clap(long)]
+        depth: Option<usize>,
+
+        /// Print in JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Show the kind, mode, size and digest of an entry of a tree in CAS
+    #[clap(arg_required_else_help = true)]
+    Stat {
+        /// The digest of the root directory
+        digest: String,
+
+        /// The path under the root
+        path: String,
+
+        /// Print in JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Write a file of a tree in CAS to stdout
+    #[clap(arg_required_else_help = true)]
+    Cat {
+        /// The digest of the root directory
+        digest: String,
+
+        /// The path of the file under the root, symlinks are followed
+        path: String,
+
+        /// Start reading at this byte
+        #[clap(long, default_value_t = 0)]
+        offset: u64,
+
+        /// Max number of bytes to read, till the end of the file by default
+        #[clap(long)]
+        length: Option<u64>,
+    },
+
+    /// Show the paths that differ between two trees in CAS
+    #[clap(arg_required_else_help = true)]
+    Diff {
+        /// The digest of the old root directory
+        old: String,
+
+        /// The digest of the new root directory
+        new: String,
+
+        /// Only print the number of changes
+        #[clap(long)]
+        stat: bool,
+
+        /// Print in JSON
+        #[clap(long)]
+        json: bool,
+
+        /// Show a unified diff of the small text files that changed
+        #[clap(long)]
+        patch: bool,
+    },
+
+    /// Print the capabilities of the CAS server
+    Capabilities {
+        /// Print in JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Inspect and control a running daemon
+    #[clap(arg_required_else_help = true)]
+    Daemon {
+        /// The control socket of the daemon
+        #[clap(long, default_value = DEFAULT_SOCKET_PATH)]
+        socket: String,
+
+        #[clap(subcommand)]
+        command: DaemonCommands,
+    },
 
     Test {
         path: String,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum DaemonCommands {
+    /// Show the mounted roots, cache and inode usage
+    Status,
+
+    /// Drop all the cached blobs
+    Flush,
+
+    /// Fetch a blob into the daemon cache ahead of reads
+    #[clap(arg_required_else_help = true)]
+    Prefetch {
+        /// The digest of the blob
+        digest: String,
+
+        /// Treat the digest as a directory and fetch the whole tree
+        #[clap(short, long)]
+        recursive: bool,
+    },
+
+    /// Swap the root of the live mount to a new tree digest
+    #[clap(arg_required_else_help = true)]
+    Swap {
+        /// The digest of the new root directory
+        digest: String,
+    },
+
+    /// Upload the changes of the writable mount and print the new root digest
+    Commit,
+
+    /// Unmount the filesystem and stop the daemon
+    Unmount,
+}
+
 fn main() -> Result<()> {
     let args = Cli::parse();
+    logging::init(args.log_format, None)?;
 
     match args.command {
-        Commands::Upload { path, out, dry_run } => cmds::upload(path, out, dry_run),
-        Commands::Download { path, digest } => cmds::download(path, digest),
+        Commands::Upload {
+            path,
+            out,
+            dry_run,
+            compression,
+            compression_level,
+            digest_function,
+            upload_streams,
+            upload_batches,
+            upload_inflight_bytes,
+            json,
+        } => cmds::upload(
+            path,
+            out,
+            dry_run,
+            json,
+            ClientOptions {
+                compression: CompressionOptions {
+                    compression,
+                    level: compression_level,
+                },
+                digest_function,
+                upload: UploadOptions {
+                    streams: upload_streams,
+                    batches: upload_batches,
+                    max_inflight_bytes: upload_inflight_bytes,
+                },
+            },
+        ),
+        Commands::Download {
+            path,
+            digest,
+            recursive,
+        } => cmds::download(path, digest, recursive),
         Commands::Mount { path, digest } => cmds::mount(path, digest),
+        Commands::Ls { digest, path, json } => cmds::ls(digest, path, json),
+        Commands::Tree {
+            digest,
+            depth,
+            json,
+        } => cmds::tree(digest, depth, json),
+        Commands::Stat { digest, path, json } => cmds::stat(digest, path, json),
+        Commands::Cat {
+            digest,
+            path,
+            offset,
+            length,
+        } => cmds::cat(digest, path, offset, length),
+        Commands::Diff {
+            old,
+            new,
+            stat,
+            json,
+            patch,
+        } => cmds::diff(old, new, stat, json, patch),
+        Commands::Capabilities { json } => cmds::capabilities(json),
+        Commands::Daemon { socket, command } => {
+            let request = match command {
+                DaemonCommands::Status => Request::Status,
+                DaemonCommands::Flush => Request::Flush,
+                DaemonCommands::Prefetch { digest, recursive } => {
+                    Request::Prefetch { digest, recursive }
+                }
+                DaemonCommands::Swap { digest } => Request::Swap { digest },
+                DaemonCommands::Commit => Request::Commit,
+                DaemonCommands::Unmount => Request::Unmount,
+            };
+            cmds::daemon(socket, request)
+        }
         Commands::Test { path } => cmds::test(path),
     }
 }
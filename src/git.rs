@@ -1,6 +1,7 @@
 use anyhow::Result;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use tracing::debug;
 
 pub fn get_git_root(path: &Path) -> Result<PathBuf> {
     let path = path
@@ -44,7 +45,7 @@ pub fn git_lfs_fetch(root: &Path, path: &Path) -> Result<()> {
     let mut cmd = Command::new("git");
     cmd.args(["-C", root, "lfs", "fetch", "-I", path]);
 
-    println!("{:?}", cmd);
+    debug!("{:?}", cmd);
 
     let output = cmd.output()?;
     if output.status.success() {
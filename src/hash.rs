@@ -1,6 +1,177 @@
 use anyhow::Result;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest as Sha2Digest, Sha256};
+use std::fs::File;
 use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+/// buffer size of the streamed hashing
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// files from this size are hashed with the reads running ahead of the
+/// hasher on another thread, or memory mapped and hashed in parallel when
+/// the hash function allows it
+pub const LARGE_FILE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// size of the blocks read ahead of the hasher
+const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// number of blocks read ahead of the hasher
+const READ_AHEAD_BLOCKS: usize = 4;
+
+/// DigestFunction is the hash function of the content digests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DigestFunction {
+    Sha256,
+    Blake3,
+}
+
+impl Default for DigestFunction {
+    fn default() -> Self {
+        DigestFunction::Sha256
+    }
+}
+
+impl FromStr for DigestFunction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(DigestFunction::Sha256),
+            "blake3" => Ok(DigestFunction::Blake3),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown digest function {}, expect one of sha256 and blake3",
+                s
+            ))),
+        }
+    }
+}
+
+impl DigestFunction {
+    /// the name in the remote execution API, eg. in the server capabilities
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestFunction::Sha256 => "SHA256",
+            DigestFunction::Blake3 => "BLAKE3",
+        }
+    }
+
+    /// the segment of the ByteStream resource names. SHA256 has none so that
+    /// the servers predating the segment still understand the names
+    pub fn resource_segment(&self) -> Option<&'static str> {
+        match self {
+            DigestFunction::Sha256 => None,
+            DigestFunction::Blake3 => Some("blake3"),
+        }
+    }
+
+    pub fn hash(&self, bytes: &[u8]) -> String {
+        let mut hasher = Hasher::new(*self);
+        hasher.update(bytes);
+        hasher.finalize()
+    }
+
+    /// generate the hash from a stream of bytes, returns the hash and the size
+    pub fn hash_read(&self, rdr: &mut dyn Read) -> Result<(String, usize)> {
+        let mut hasher = Hasher::new(*self);
+        let mut buff = vec![0; READ_BUFFER_SIZE];
+        let mut len = 0;
+        loop {
+            let size = rdr.read(&mut buff)?;
+            if size == 0 {
+                break;
+            }
+            len += size;
+            hasher.update(&buff[0..size]);
+        }
+        Ok((hasher.finalize(), len))
+    }
+
+    /// generate the hash of a file, returns the hash and the size.
+    ///
+    /// BLAKE3 hashes the large files memory mapped across the rayon threads.
+    /// SHA256 cannot be parallelized within a file, so the large files are
+    /// read ahead on another thread while the previous blocks are hashed.
+    pub fn hash_file(&self, path: &Path) -> Result<(String, usize)> {
+        let mut f = File::open(path)
+            .map_err(|e| anyhow::Error::msg(format!("failed to open {:?}: {}", path, e)))?;
+        let len = f.metadata()?.len();
+        if len < LARGE_FILE_SIZE {
+            return self.hash_read(&mut f);
+        }
+
+        match self {
+            DigestFunction::Blake3 => {
+                // the mapping is only read, and the file is expected to
+                // stay unchanged while it is being uploaded
+                let mmap = unsafe { Mmap::map(&f)? };
+                let mut hasher = blake3::Hasher::new();
+                hasher.update_rayon(&mmap);
+                Ok((hasher.finalize().to_hex().to_string(), mmap.len()))
+            }
+            DigestFunction::Sha256 => self.hash_read_ahead(f),
+        }
+    }
+
+    /// hash with the blocks read by another thread, so that the reads
+    /// overlap with the hashing
+    fn hash_read_ahead<R: Read + Send + 'static>(&self, mut rdr: R) -> Result<(String, usize)> {
+        let (send, recv) = mpsc::sync_channel::<std::io::Result<Vec<u8>>>(READ_AHEAD_BLOCKS);
+        let reader = thread::spawn(move || loop {
+            let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+            let res = (&mut rdr).take(BLOCK_SIZE).read_to_end(&mut block);
+            let done = !matches!(res, Ok(size) if size > 0);
+            // the hasher is gone on error
+            if send.send(res.map(|_| block)).is_err() || done {
+                break;
+            }
+        });
+
+        let mut hasher = Hasher::new(*self);
+        let mut len = 0;
+        for block in recv {
+            let block = block?;
+            len += block.len();
+            hasher.update(&block);
+        }
+        let _ = reader.join();
+        Ok((hasher.finalize(), len))
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(blake3::Hasher),
+}
+
+impl Hasher {
+    fn new(f: DigestFunction) -> Hasher {
+        match f {
+            DigestFunction::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestFunction::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(bytes),
+            Hasher::Blake3(h) => {
+                h.update(bytes);
+            }
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format!("{:02x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
 
 pub fn sha256(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
@@ -10,26 +181,13 @@ pub fn sha256(bytes: &[u8]) -> String {
 
 /// generate the sha256 hash from a stream of bytes
 pub fn sha256_read(rdr: &mut dyn Read) -> Result<(String, usize)> {
-    let mut hasher = Sha256::new();
-    //TODO: larger block size?
-    //let mut buff: [u8; 64] = [0; 64];
-    let mut buff: [u8; 8192] = [0; 8192];
-    let mut len = 0;
-    loop {
-        let size = rdr.read(&mut buff)?;
-        if size == 0 {
-            break;
-        }
-        len += size;
-        hasher.update(&buff[0..size]);
-    }
-    let hash = format!("{:02x}", hasher.finalize());
-    Ok((hash, len))
+    DigestFunction::Sha256.hash_read(rdr)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
     use std::io::Cursor;
 
     #[test]
@@ -54,4 +212,62 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_digest_functions() {
+        assert_eq!(
+            DigestFunction::Sha256.hash(b"hello world"),
+            sha256(b"hello world")
+        );
+        assert_eq!(
+            DigestFunction::Blake3.hash(b"hello world"),
+            "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24"
+        );
+
+        let mut input = Cursor::new("hello world".as_bytes());
+        let (hash, size) = DigestFunction::Blake3.hash_read(&mut input).unwrap();
+        assert_eq!(hash, DigestFunction::Blake3.hash(b"hello world"));
+        assert_eq!(size, 11);
+    }
+
+    #[test]
+    fn test_hash_large_file() {
+        // not a multiple of the block size to cover the last partial block
+        let data: Vec<u8> = (0..LARGE_FILE_SIZE + BLOCK_SIZE / 2 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let dir = TempDir::new("hash").unwrap();
+        let path = dir.path().join("large");
+        std::fs::write(&path, &data).unwrap();
+
+        for f in [DigestFunction::Sha256, DigestFunction::Blake3] {
+            let (hash, size) = f.hash_file(&path).unwrap();
+            assert_eq!(hash, f.hash(&data));
+            assert_eq!(size, data.len());
+        }
+    }
+
+    #[test]
+    fn test_hash_read_ahead() {
+        let (hash, size) = DigestFunction::Sha256
+            .hash_read_ahead(Cursor::new(b"hello world".to_vec()))
+            .unwrap();
+        assert_eq!(hash, sha256(b"hello world"));
+        assert_eq!(size, 11);
+
+        let (hash, size) = DigestFunction::Sha256
+            .hash_read_ahead(Cursor::new(vec![]))
+            .unwrap();
+        assert_eq!(hash, sha256(b""));
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn test_parse_digest_function() {
+        assert_eq!(
+            "BLAKE3".parse::<DigestFunction>().unwrap(),
+            DigestFunction::Blake3
+        );
+        assert!("md5".parse::<DigestFunction>().is_err());
+    }
 }
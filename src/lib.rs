@@ -1,4 +1,9 @@
 pub mod cas;
+pub mod control;
 pub mod git;
 pub mod hash;
 pub mod lfs;
+pub mod logging;
+pub mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
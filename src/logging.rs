@@ -0,0 +1,84 @@
+//! Logging of the binaries.
+//!
+//! The logs are structured with `tracing` and filtered by `RUST_LOG`,
+//! `info` by default. The FUSE ops and the CAS RPCs are spans at the debug
+//! level, eg. `RUST_LOG=cfs=debug,cfsd=debug`, which are logged when they
+//! close along with the time they took.
+use anyhow::Result;
+use std::fs::OpenOptions;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Format of the log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown log format {}, expect one of text and json",
+                s
+            ))),
+        }
+    }
+}
+
+/// set up the logging of the process, the logs go to stderr or are
+/// appended to `file` when given
+pub fn init(format: LogFormat, file: Option<&str>) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    let res = match (format, file) {
+        (LogFormat::Text, None) => builder.with_writer(io::stderr).try_init(),
+        (LogFormat::Json, None) => builder.json().with_writer(io::stderr).try_init(),
+        (LogFormat::Text, Some(path)) => builder
+            .with_ansi(false)
+            .with_writer(Mutex::new(open(path)?))
+            .try_init(),
+        (LogFormat::Json, Some(path)) => builder
+            .json()
+            .with_writer(Mutex::new(open(path)?))
+            .try_init(),
+    };
+    res.map_err(|e| anyhow::Error::msg(format!("failed to set up logging {}", e)))
+}
+
+fn open(path: &str) -> Result<std::fs::File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::Error::msg(format!("failed to open log file {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Prometheus metrics.
+//!
+//! The metrics are registered in the default registry of the process and
+//! updated by the CAS clients and the FUSE ops. `cfsd` serves them in the
+//! Prometheus text format with `--metrics_addr`.
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    /// 50us to 13s, the cached FUSE ops take microseconds and the CAS RPCs
+    /// of large blobs take seconds
+    static ref LATENCY_BUCKETS: Vec<f64> = exponential_buckets(0.00005, 4.0, 10).unwrap();
+
+    pub static ref FUSE_OP_DURATION: HistogramVec = register_histogram_vec!(
+        "cfs_fuse_op_duration_seconds",
+        "Latency of the FUSE ops by op",
+        &["op"],
+        LATENCY_BUCKETS.clone()
+    )
+    .unwrap();
+
+    pub static ref CAS_RPC_DURATION: HistogramVec = register_histogram_vec!(
+        "cfs_cas_rpc_duration_seconds",
+        "Latency of the CAS RPCs by method",
+        &["method"],
+        LATENCY_BUCKETS.clone()
+    )
+    .unwrap();
+
+    /// the code is the gRPC status code, or the kind of the error when it
+    /// did not come from the server
+    pub static ref CAS_RPC_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "cfs_cas_rpc_errors_total",
+        "Failed CAS RPCs by method and code",
+        &["method", "code"]
+    )
+    .unwrap();
+
+    pub static ref CAS_READ_BYTES: IntCounter = register_int_counter!(
+        "cfs_cas_read_bytes_total",
+        "Bytes of the blobs fetched from CAS"
+    )
+    .unwrap();
+
+    pub static ref CACHE_HITS: IntCounter =
+        register_int_counter!("cfs_cache_hits_total", "Blobs read from the cache").unwrap();
+
+    pub static ref CACHE_MISSES: IntCounter =
+        register_int_counter!("cfs_cache_misses_total", "Blobs fetched from CAS on read").unwrap();
+
+    /// the blobs are only dropped from the cache on flush
+    pub static ref CACHE_EVICTIONS: IntCounter = register_int_counter!(
+        "cfs_cache_evictions_total",
+        "Blobs dropped from the cache"
+    )
+    .unwrap();
+
+    pub static ref CACHE_IN_FLIGHT: IntGauge =
+        register_int_gauge!("cfs_cache_in_flight", "Blobs being fetched from CAS").unwrap();
+
+    /// set when the metrics are scraped
+    pub static ref CACHE_BYTES: IntGauge =
+        register_int_gauge!("cfs_cache_bytes", "Total size of the cached blobs").unwrap();
+
+    /// set when the metrics are scraped
+    pub static ref CACHE_BLOBS: IntGauge =
+        register_int_gauge!("cfs_cache_blobs", "Number of the cached blobs").unwrap();
+
+    /// set when the metrics are scraped
+    pub static ref INODES: IntGauge =
+        register_int_gauge!("cfs_inodes", "Size of the inode table").unwrap();
+}
+
+/// the metrics of the process in the Prometheus text format
+pub fn encode() -> Result<String> {
+    let mut buff = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buff)?;
+    Ok(String::from_utf8(buff)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        FUSE_OP_DURATION
+            .with_label_values(&["lookup"])
+            .observe(0.001);
+        CACHE_HITS.inc();
+        let text = encode().unwrap();
+        assert!(text.contains("cfs_fuse_op_duration_seconds_count{op=\"lookup\"}"));
+        assert!(text.contains("# TYPE cfs_cache_hits_total counter"));
+    }
+}
@@ -0,0 +1,39 @@
+//! Helpers shared by the tests of the crate and of the binaries.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// TempDir is a scratch directory of a test, removed on drop so that it
+/// does not leak when the test fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// create an empty directory, unique across the tests of the process
+    pub fn new(name: &str) -> io::Result<TempDir> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "cfs-{}-{}-{}",
+            name,
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}